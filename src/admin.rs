@@ -0,0 +1,95 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    serve::{AppState, Secret},
+};
+
+/// Access to routes which change who is mailed, like the suppression list
+///
+/// | Setting name | Value                                    | Default value       |
+/// | ------------ | ---------------------------------------- | ------------------- |
+/// | token        | bearer token the routes require          | unset (routes off)  |
+/// | token.file   | path to a file containing the token      | unset               |
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct AdminConfig {
+    pub token: Option<Secret>,
+}
+
+/// Checks requests to admin routes for the configured bearer token
+#[derive(Debug, Default)]
+pub(crate) struct Admin {
+    /// A hash of the token, so comparing it takes the same time however much matches
+    token: Option<[u8; 32]>,
+}
+
+impl Admin {
+    pub(crate) fn new(config: AdminConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            token: config
+                .token
+                .map(|token| token.read())
+                .transpose()?
+                .map(|token| Sha256::digest(token).into()),
+        })
+    }
+
+    fn check(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let Some(token) = &self.token else {
+            return Err(ApiError::not_found("Admin routes are not enabled"));
+        };
+        let given = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if <[u8; 32]>::from(Sha256::digest(given.trim())) == *token => Ok(()),
+            _ => Err(ApiError::new(
+                ErrorCode::Unauthorized,
+                "Admin routes need the admin token as a bearer token",
+            )),
+        }
+    }
+}
+
+/// Lets requests through to admin routes only with the admin token
+pub(crate) async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match state.admin.check(request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(error) => error.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_required() {
+        let admin = Admin::new(AdminConfig {
+            token: Some(Secret::Inline("hunter2".to_owned())),
+        })
+        .unwrap();
+        let headers = |value: &str| HeaderMap::from_iter([(AUTHORIZATION, value.parse().unwrap())]);
+        assert!(admin.check(&headers("Bearer hunter2")).is_ok());
+        let error = admin.check(&headers("Bearer hunter3")).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Unauthorized);
+        assert!(admin.check(&HeaderMap::new()).is_err());
+
+        let error = Admin::default()
+            .check(&headers("Bearer hunter2"))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::NotFound);
+    }
+}
@@ -0,0 +1,74 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use lettre::message::{header::ContentType, MultiPart, SinglePart};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::send::SendError;
+
+/// A file to attach to an email, or to embed in its HTML body
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+pub struct Attachment {
+    /// Name of the file, as shown to the recipient
+    filename: String,
+    /// MIME type of the file, such as `application/pdf` or `image/png`
+    content_type: String,
+    /// The file's contents, base64 encoded
+    content: String,
+    /// Embeds the file in the HTML body rather than attaching it.
+    /// It can be referred to in the body as `cid:<content_id>`,
+    /// for example `<img src="cid:cover">`.
+    content_id: Option<String>,
+}
+
+impl Attachment {
+    fn into_part(self) -> Result<SinglePart, SendError> {
+        let Attachment {
+            filename,
+            content_type,
+            content,
+            content_id,
+        } = self;
+        let bad = |reason: String| SendError::AttachmentError {
+            filename: filename.clone(),
+            reason,
+        };
+        let content_type = ContentType::parse(&content_type).map_err(|e| bad(e.to_string()))?;
+        let content: String = content.split_ascii_whitespace().collect();
+        let content = BASE64.decode(content).map_err(|e| bad(e.to_string()))?;
+
+        let attachment = match content_id {
+            Some(content_id) => lettre::message::Attachment::new_inline(content_id),
+            None => lettre::message::Attachment::new(filename),
+        };
+        Ok(attachment.body(content, content_type))
+    }
+}
+
+/// Wraps a message body with its attachments
+///
+/// Embedded files go alongside the body in `multipart/related`, and
+/// everything else is added after it in `multipart/mixed`.
+pub(crate) fn with_attachments(
+    mut body: MultiPart,
+    attachments: Vec<Attachment>,
+) -> Result<MultiPart, SendError> {
+    let (inline, attached): (Vec<_>, Vec<_>) = attachments
+        .into_iter()
+        .partition(|attachment| attachment.content_id.is_some());
+
+    if !inline.is_empty() {
+        let mut related = MultiPart::related().multipart(body);
+        for attachment in inline {
+            related = related.singlepart(attachment.into_part()?);
+        }
+        body = related;
+    }
+    if !attached.is_empty() {
+        let mut mixed = MultiPart::mixed().multipart(body);
+        for attachment in attached {
+            mixed = mixed.singlepart(attachment.into_part()?);
+        }
+        body = mixed;
+    }
+    Ok(body)
+}
@@ -0,0 +1,533 @@
+use axum::{body::Bytes, extract::State};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use lettre::Address;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    extract::{Json, Query},
+    serve::{AppState, Secret},
+};
+
+/// VERP return paths, so bounces can be traced to what was sent
+///
+/// | Setting name | Value                                            | Default value |
+/// | ------------ | ------------------------------------------------ | ------------- |
+/// | domain       | domain whose mail is passed to `POST /bounces`   | unset (off)   |
+/// | prefix       | local part bounce addresses start with           | `bounces`     |
+/// | secret       | key used to sign bounce addresses                | unset         |
+/// | secret.file  | path to a file containing the key                | unset         |
+///
+/// Mail from template `edit-note` to `jade@example.org` is sent with the
+/// envelope sender `bounces+edit-note.jade=example.org.<signature>@<domain>`.
+/// Mail to several recipients only carries the template, as in
+/// `bounces+edit-note.<signature>@<domain>`, and raw MJML mail uses `mjml`.
+/// So does mail whose local part would otherwise be longer than SMTP allows.
+/// The secret is required when a domain is set.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct BounceConfig {
+    pub domain: Option<String>,
+    pub prefix: String,
+    pub secret: Option<Secret>,
+}
+
+impl Default for BounceConfig {
+    fn default() -> Self {
+        Self {
+            domain: None,
+            prefix: "bounces".to_owned(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BounceSetupError {
+    #[error("Failed to read bounce secret: {0}")]
+    Secret(#[from] std::io::Error),
+    #[error("A bounce secret is needed to sign the addresses of the bounce domain")]
+    NoSecret,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of the HMAC kept in a bounce address, written as hex
+const SIGNATURE_LEN: usize = 6;
+
+/// The longest local part an address can have (RFC 5321 section 4.5.3.1.1)
+const MAX_LOCAL_PART: usize = 64;
+
+/// Encodes and decodes VERP bounce addresses
+///
+/// Addresses are signed, so a bounce can only name a recipient mail was
+/// really sent to.
+#[derive(Debug)]
+pub(crate) struct Bounces {
+    domain: Option<String>,
+    prefix: String,
+    key: Vec<u8>,
+}
+
+impl Default for Bounces {
+    fn default() -> Self {
+        Self::new(BounceConfig::default()).expect("bounce tracking is off by default")
+    }
+}
+
+impl Bounces {
+    pub(crate) fn new(config: BounceConfig) -> Result<Self, BounceSetupError> {
+        let key = config.secret.map(|secret| secret.read()).transpose()?;
+        if config.domain.is_some() && key.is_none() {
+            return Err(BounceSetupError::NoSecret);
+        }
+        Ok(Self {
+            domain: config.domain,
+            prefix: config.prefix,
+            key: key.unwrap_or_default().into_bytes(),
+        })
+    }
+
+    fn mac(&self, tag: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        // Relays may change the case of local parts
+        mac.update(tag.to_lowercase().as_bytes());
+        mac
+    }
+
+    /// The tag of a bounce address, followed by its signature
+    fn sign(&self, tag: &str) -> String {
+        let signature = self.mac(tag).finalize().into_bytes();
+        let hex: String = signature[..SIGNATURE_LEN]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("{tag}.{hex}")
+    }
+
+    /// The tag of a signed bounce address, if the signature matches
+    fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+        let (tag, hex) = signed.rsplit_once('.')?;
+        if hex.len() != SIGNATURE_LEN * 2 || !hex.is_ascii() {
+            return None;
+        }
+        let signature = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        self.mac(tag).verify_truncated_left(&signature).ok()?;
+        Some(tag)
+    }
+
+    /// The envelope sender for a message, if bounce tracking is enabled
+    pub(crate) fn return_path(
+        &self,
+        template_id: Option<&str>,
+        recipients: &[Address],
+    ) -> Option<Address> {
+        let domain = self.domain.as_ref()?;
+        let template_id = template_id.unwrap_or("mjml");
+        let prefix = &self.prefix;
+        let local = [
+            match recipients {
+                [recipient] => Some(format!(
+                    "{prefix}+{}",
+                    self.sign(&format!(
+                        "{template_id}.{}={}",
+                        recipient.user(),
+                        recipient.domain()
+                    ))
+                )),
+                _ => None,
+            },
+            Some(format!("{prefix}+{}", self.sign(template_id))),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|local| local.len() <= MAX_LOCAL_PART)
+        .unwrap_or_else(|| prefix.clone());
+        format!("{local}@{domain}").parse().ok()
+    }
+
+    /// The template and recipient encoded in a bounce address, if it was signed by us
+    fn decode(&self, address: &str) -> Option<(String, Option<String>)> {
+        self.domain.as_ref()?;
+        let address = address.trim().trim_start_matches('<').trim_end_matches('>');
+        let (local, _domain) = address.rsplit_once('@')?;
+        let tag = local.strip_prefix(&self.prefix)?.strip_prefix('+')?;
+        let tag = self.verify(tag)?;
+        // Template IDs never contain `.`, and domains never contain `=`
+        Some(match tag.split_once('.') {
+            Some((template_id, recipient)) => {
+                let recipient = recipient
+                    .rsplit_once('=')
+                    .map(|(user, domain)| format!("{user}@{domain}"));
+                (template_id.to_owned(), recipient)
+            }
+            None => (tag.to_owned(), None),
+        })
+    }
+}
+
+/// A header block, unfolded, with lowercased field names
+fn parse_fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in block.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    fields
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Splits a message or MIME part into its header block and body
+fn split_headers(raw: &str) -> (&str, &str) {
+    match raw.find("\n\n") {
+        Some(end) => (&raw[..end], &raw[end + 2..]),
+        None => (raw, ""),
+    }
+}
+
+/// The lowercased MIME type of a Content-Type value, and its boundary if any
+fn parse_content_type(value: &str) -> (String, Option<String>) {
+    let mut params = value.split(';');
+    let mime = params
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        (name.trim().eq_ignore_ascii_case("boundary"))
+            .then(|| value.trim().trim_matches('"').to_owned())
+    });
+    (mime, boundary)
+}
+
+/// Finds the `message/delivery-status` part of a message, however deeply nested
+fn delivery_status(raw: &str) -> Option<String> {
+    let (headers, body) = split_headers(raw);
+    let fields = parse_fields(headers);
+    let (mime, boundary) =
+        parse_content_type(field(&fields, "content-type").unwrap_or("text/plain"));
+    match mime.as_str() {
+        "message/delivery-status" | "message/global-delivery-status" => {
+            match field(&fields, "content-transfer-encoding") {
+                Some(encoding) if encoding.eq_ignore_ascii_case("base64") => {
+                    let encoded: String = body.split_ascii_whitespace().collect();
+                    let decoded = BASE64.decode(encoded).ok()?;
+                    Some(String::from_utf8_lossy(&decoded).replace("\r\n", "\n"))
+                }
+                _ => Some(body.to_owned()),
+            }
+        }
+        mime if mime.starts_with("multipart/") => {
+            let delimiter = format!("--{}", boundary?);
+            body.split(&delimiter)
+                // Skip the preamble, and stop at the closing delimiter
+                .skip(1)
+                .take_while(|part| !part.starts_with("--"))
+                .filter_map(|part| part.split_once('\n').map(|(_, part)| part))
+                .find_map(delivery_status)
+        }
+        _ => None,
+    }
+}
+
+/// One recipient's entry in a delivery status notification
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Bounce {
+    /// The address which could not be delivered to
+    recipient: String,
+    /// `failed` or `delayed`, as reported by the remote server
+    action: String,
+    /// Enhanced status code, such as `5.1.1`
+    status: String,
+    /// The remote server's explanation, if given
+    diagnostic: Option<String>,
+    /// Whether the address was added to the suppression list
+    suppressed: bool,
+}
+
+/// What was learned from a delivery status notification
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct BounceReport {
+    /// The template of the bounced mail, from its VERP address
+    template_id: Option<String>,
+    bounces: Vec<Bounce>,
+}
+
+/// The value of a typed field like `smtp; 550 User unknown`, without its type
+fn typed_field(value: &str) -> &str {
+    value
+        .split_once(';')
+        .map_or(value, |(_, value)| value)
+        .trim()
+}
+
+/// The value of an address field like `rfc822; jade@example.org`
+fn address_field(value: &str) -> String {
+    typed_field(value)
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_owned()
+}
+
+/// Parses an RFC 3464 delivery status notification
+///
+/// `verp_recipient` replaces the reported address of a single bounced
+/// recipient, as it is exactly what was sent to, while the reported
+/// address may have been rewritten by forwarding.
+fn parse_dsn(raw: &str, verp_recipient: Option<&str>) -> Option<Vec<Bounce>> {
+    let raw = raw.replace("\r\n", "\n");
+    let status = delivery_status(&raw)?;
+    let groups: Vec<_> = status
+        .split("\n\n")
+        .map(parse_fields)
+        .filter(|fields| {
+            field(fields, "final-recipient").is_some()
+                || field(fields, "original-recipient").is_some()
+        })
+        .collect();
+    let single = groups.len() == 1;
+    Some(
+        groups
+            .iter()
+            .filter_map(|fields| {
+                let reported = field(fields, "original-recipient")
+                    .or(field(fields, "final-recipient"))
+                    .map(address_field)?;
+                let recipient = match verp_recipient {
+                    Some(recipient) if single => recipient.to_owned(),
+                    _ => reported,
+                };
+                Some(Bounce {
+                    recipient,
+                    action: field(fields, "action")?.to_ascii_lowercase(),
+                    status: field(fields, "status")
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned(),
+                    diagnostic: field(fields, "diagnostic-code")
+                        .map(|value| typed_field(value).to_owned()),
+                    suppressed: false,
+                })
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct BounceQuery {
+    /// The address the notification was delivered to.
+    /// If not given, it is taken from the `Delivered-To`,
+    /// `X-Original-To` or `To` header.
+    recipient: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/bounces",
+    responses(
+        (status = 200, description = "The notification was processed", body = BounceReport),
+        (status = UNPROCESSABLE_ENTITY, description = "The message is not a delivery status notification"),
+    ),
+    params(BounceQuery),
+    request_body(content = String, content_type = "message/rfc822", description = "The raw notification message"),
+)]
+pub async fn bounce_route(
+    State(state): State<AppState>,
+    Query(BounceQuery { recipient }): Query<BounceQuery>,
+    body: Bytes,
+) -> Result<Json<BounceReport>, ApiError> {
+    let raw = String::from_utf8_lossy(&body);
+    let fields = parse_fields(split_headers(&raw.replace("\r\n", "\n")).0);
+    let (template_id, verp_recipient) = recipient
+        .as_deref()
+        .or(field(&fields, "delivered-to"))
+        .or(field(&fields, "x-original-to"))
+        .or(field(&fields, "to"))
+        .and_then(|address| state.bounces.decode(address))
+        .unzip();
+    let verp_recipient = verp_recipient.flatten();
+
+    let mut bounces = parse_dsn(&raw, verp_recipient.as_deref()).ok_or_else(|| {
+        ApiError::new(ErrorCode::BadMessage, "Not a delivery status notification")
+    })?;
+    let template = template_id.clone().unwrap_or_else(|| "unknown".to_owned());
+    for bounce in &mut bounces {
+        counter!("mails_bounced_total", "template" => template.clone(), "action" => bounce.action.clone())
+            .increment(1);
+        if bounce.action != "failed" || !bounce.status.starts_with('5') {
+            continue;
+        }
+        info!(
+            "{} bounced {template} mail with {}",
+            bounce.recipient, bounce.status
+        );
+        // Only a signed address shows the recipient was really mailed
+        if !verp_recipient
+            .as_deref()
+            .is_some_and(|recipient| recipient.eq_ignore_ascii_case(&bounce.recipient))
+        {
+            warn!(
+                "Not suppressing {}, as the bounce wasn't sent to its bounce address",
+                bounce.recipient
+            );
+            continue;
+        }
+        let reason = match &bounce.diagnostic {
+            Some(diagnostic) => format!("Bounced with {}: {diagnostic}", bounce.status),
+            None => format!("Bounced with {}", bounce.status),
+        };
+        match state.suppressions.add(&bounce.recipient, reason).await {
+            Ok(_) => bounce.suppressed = true,
+            Err(e) => warn!("Failed to save suppression of {}: {e}", bounce.recipient),
+        }
+    }
+    Ok(Json(BounceReport {
+        template_id,
+        bounces,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounces() -> Bounces {
+        Bounces::new(BounceConfig {
+            domain: Some("bounces.musicbrainz.org".to_owned()),
+            secret: Some(Secret::Inline("hunter2".to_owned())),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn verp_address_round_trips() {
+        let bounces = bounces();
+        let recipient: Address = "jade.doe@example.org".parse().unwrap();
+        let address = bounces
+            .return_path(Some("edit-note"), &[recipient])
+            .unwrap();
+        assert!(address
+            .user()
+            .starts_with("bounces+edit-note.jade.doe=example.org."));
+        assert_eq!(address.domain(), "bounces.musicbrainz.org");
+        assert_eq!(
+            bounces.decode(address.as_ref()),
+            Some((
+                "edit-note".to_owned(),
+                Some("jade.doe@example.org".to_owned())
+            ))
+        );
+        let address = bounces.return_path(None, &[]).unwrap();
+        assert_eq!(
+            bounces.decode(address.as_ref()),
+            Some(("mjml".to_owned(), None))
+        );
+        assert_eq!(bounces.decode("someone@example.org"), None);
+    }
+
+    #[test]
+    fn unsigned_verp_address_is_not_trusted() {
+        let bounces = bounces();
+        assert_eq!(
+            bounces.decode("bounces+edit-note.rob=example.org@bounces.musicbrainz.org"),
+            None
+        );
+        let recipient: Address = "jade@example.org".parse().unwrap();
+        let address = bounces
+            .return_path(Some("edit-note"), &[recipient])
+            .unwrap();
+        let forged = address.as_ref().replace("jade", "rob");
+        assert_eq!(bounces.decode(&forged), None);
+
+        assert!(matches!(
+            Bounces::new(BounceConfig {
+                domain: Some("bounces.musicbrainz.org".to_owned()),
+                ..Default::default()
+            }),
+            Err(BounceSetupError::NoSecret)
+        ));
+    }
+
+    #[test]
+    fn long_verp_address_leaves_out_recipient() {
+        let bounces = bounces();
+        let recipient: Address = format!("{}@example.org", "j".repeat(50)).parse().unwrap();
+        let address = bounces
+            .return_path(Some("edit-note"), &[recipient])
+            .unwrap();
+        assert!(address.user().starts_with("bounces+edit-note."));
+        assert_eq!(
+            bounces.decode(address.as_ref()),
+            Some(("edit-note".to_owned(), None))
+        );
+
+        let bounces = Bounces::new(BounceConfig {
+            domain: Some("bounces.musicbrainz.org".to_owned()),
+            prefix: "b".repeat(60),
+            secret: Some(Secret::Inline("hunter2".to_owned())),
+        })
+        .unwrap();
+        let address = bounces.return_path(Some("edit-note"), &[]).unwrap();
+        assert_eq!(address.user(), "b".repeat(60));
+    }
+
+    #[test]
+    fn hard_bounce_is_parsed() {
+        let raw = include_str!("../fixtures/dsn/hard_bounce.eml");
+        let bounces = parse_dsn(raw, None).unwrap();
+        assert_eq!(
+            bounces,
+            vec![Bounce {
+                recipient: "jade@example.org".to_owned(),
+                action: "failed".to_owned(),
+                status: "5.1.1".to_owned(),
+                diagnostic: Some(
+                    "550 5.1.1 <jade@example.org>: Recipient address rejected: User unknown"
+                        .to_owned()
+                ),
+                suppressed: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn delayed_delivery_is_parsed() {
+        let raw = include_str!("../fixtures/dsn/delayed.eml");
+        let bounces = parse_dsn(raw, Some("rob@example.org")).unwrap();
+        assert_eq!(bounces.len(), 1);
+        assert_eq!(bounces[0].recipient, "rob@example.org");
+        assert_eq!(bounces[0].action, "delayed");
+        assert_eq!(bounces[0].status, "4.4.1");
+    }
+
+    #[test]
+    fn other_mail_is_not_a_dsn() {
+        let raw = "From: jade@example.org\nSubject: Hi\n\nHello";
+        assert_eq!(parse_dsn(raw, None), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use lettre::{
+    address::{Address, AddressError},
+    message::Mailbox,
+};
+
+use crate::templates;
+
+/// Default addressing for the mail of each template or site
+///
+/// | Setting name | Value                                                          | Default value |
+/// | ------------ | -------------------------------------------------------------- | ------------- |
+/// | domains      | list of domains mail can be from, including their subdomains   | empty (any)   |
+/// | defaults     | map of template ID or site name to default addresses           | empty         |
+///
+/// A template's own defaults are used before those of its site, such as
+/// `musicbrainz` or `listenbrainz`. Addresses given in a request override both.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct BrandConfig {
+    pub domains: Vec<String>,
+    pub defaults: HashMap<String, SenderConfig>,
+}
+
+/// | Setting name      | Value                                          |
+/// | ----------------- | ---------------------------------------------- |
+/// | from              | the From address                               |
+/// | sender            | the Sender address                             |
+/// | reply_to          | the Reply-To address                           |
+/// | return_path       | the envelope sender, for bounces               |
+/// | message_id_domain | domain of generated Message-IDs, if not From's |
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct SenderConfig {
+    pub from: Option<String>,
+    pub sender: Option<String>,
+    pub reply_to: Option<String>,
+    pub return_path: Option<String>,
+    pub message_id_domain: Option<String>,
+}
+
+/// Parsed default addresses for a template or site
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SenderDefaults {
+    pub from: Option<Mailbox>,
+    pub sender: Option<Mailbox>,
+    pub reply_to: Option<Mailbox>,
+    pub return_path: Option<Address>,
+    pub message_id_domain: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BrandError {
+    #[error("Invalid {field} address {address:?} in the defaults for {key}: {source}")]
+    Address {
+        key: String,
+        field: &'static str,
+        address: String,
+        source: AddressError,
+    },
+    #[error("The defaults for {key} send from {domain}, which is not an allowed domain")]
+    Domain { key: String, domain: String },
+}
+
+/// Default addresses, and the domains mail may be sent from
+#[derive(Debug, Default)]
+pub(crate) struct Brands {
+    /// Lowercased domains
+    domains: Vec<String>,
+    defaults: HashMap<String, SenderDefaults>,
+}
+
+impl Brands {
+    pub(crate) fn new(config: BrandConfig) -> Result<Self, BrandError> {
+        let mut brands = Self {
+            domains: config
+                .domains
+                .iter()
+                .map(|domain| domain.trim().to_lowercase())
+                .collect(),
+            defaults: HashMap::new(),
+        };
+        for (key, sender) in config.defaults {
+            let parse = |field: &'static str, address: Option<String>| {
+                address
+                    .map(|address| {
+                        address.parse().map_err(|source| BrandError::Address {
+                            key: key.clone(),
+                            field,
+                            address,
+                            source,
+                        })
+                    })
+                    .transpose()
+            };
+            let defaults = SenderDefaults {
+                from: parse("from", sender.from)?,
+                sender: parse("sender", sender.sender)?,
+                reply_to: parse("reply_to", sender.reply_to)?,
+                return_path: parse("return_path", sender.return_path)?
+                    .map(|mailbox: Mailbox| mailbox.email),
+                message_id_domain: sender.message_id_domain,
+            };
+            for mailbox in defaults.from.iter().chain(&defaults.sender) {
+                if !brands.allows(&mailbox.email) {
+                    return Err(BrandError::Domain {
+                        key,
+                        domain: mailbox.email.domain().to_owned(),
+                    });
+                }
+            }
+            brands.defaults.insert(key, defaults);
+        }
+        Ok(brands)
+    }
+
+    /// Whether mail can be sent from an address
+    pub(crate) fn allows(&self, address: &Address) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+        let domain = address.domain().to_lowercase();
+        self.domains.iter().any(|allowed| {
+            domain == *allowed
+                || domain
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+
+    /// The defaults for a template, falling back to those of its site
+    pub(crate) fn defaults(&self, template_id: Option<&str>) -> SenderDefaults {
+        let Some(template_id) = template_id else {
+            return SenderDefaults::default();
+        };
+        let template = self.defaults.get(template_id);
+        let site = templates::site(template_id).and_then(|site| self.defaults.get(site));
+        let pick = |f: fn(&SenderDefaults) -> Option<Mailbox>| {
+            template.and_then(f).or_else(|| site.and_then(f))
+        };
+        SenderDefaults {
+            from: pick(|d| d.from.clone()),
+            sender: pick(|d| d.sender.clone()),
+            reply_to: pick(|d| d.reply_to.clone()),
+            return_path: template
+                .and_then(|d| d.return_path.clone())
+                .or_else(|| site.and_then(|d| d.return_path.clone())),
+            message_id_domain: template
+                .and_then(|d| d.message_id_domain.clone())
+                .or_else(|| site.and_then(|d| d.message_id_domain.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brands() -> Brands {
+        Brands::new(BrandConfig {
+            domains: vec!["musicbrainz.org".to_owned(), "listenbrainz.org".to_owned()],
+            defaults: HashMap::from([
+                (
+                    "musicbrainz".to_owned(),
+                    SenderConfig {
+                        from: Some("MusicBrainz <noreply@musicbrainz.org>".to_owned()),
+                        reply_to: Some("support@musicbrainz.org".to_owned()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "verify-email".to_owned(),
+                    SenderConfig {
+                        from: Some("accounts@musicbrainz.org".to_owned()),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn template_defaults_override_site_defaults() {
+        let brands = brands();
+        let defaults = brands.defaults(Some("verify-email"));
+        assert_eq!(
+            defaults.from.unwrap().email.to_string(),
+            "accounts@musicbrainz.org"
+        );
+        assert_eq!(
+            defaults.reply_to.unwrap().email.to_string(),
+            "support@musicbrainz.org"
+        );
+        assert!(brands.defaults(Some("recording-pin")).from.is_none());
+    }
+
+    #[test]
+    fn only_allowed_domains_can_send() {
+        let brands = brands();
+        assert!(brands.allows(&"noreply@MusicBrainz.org".parse().unwrap()));
+        assert!(brands.allows(&"noreply@test.musicbrainz.org".parse().unwrap()));
+        assert!(!brands.allows(&"noreply@evilmusicbrainz.org".parse().unwrap()));
+        assert!(!brands.allows(&"noreply@example.org".parse().unwrap()));
+
+        let spoofed = Brands::new(BrandConfig {
+            domains: vec!["musicbrainz.org".to_owned()],
+            defaults: HashMap::from([(
+                "listenbrainz".to_owned(),
+                SenderConfig {
+                    from: Some("noreply@listenbrainz.org".to_owned()),
+                    ..Default::default()
+                },
+            )]),
+        });
+        assert!(matches!(spoofed, Err(BrandError::Domain { .. })));
+    }
+}
@@ -1,9 +1,12 @@
+use mf1::t_l_string as tl;
 use mrml::fragment::Fragment;
 use mrml::mj_body::MjBodyChild;
 use mrml::mj_head::MjHeadChild;
 use mrmx::view;
 use mrmx::WithAttribute;
 
+use crate::Locale;
+
 pub fn head() -> Fragment<MjHeadChild> {
     view!(
         <>
@@ -48,3 +51,18 @@ pub fn lb_header() -> mrml::fragment::Fragment<MjBodyChild> {
 
     )
 }
+/// Warns that a mail from a staging server was redirected away from its recipients
+pub fn staging_banner(recipients: &str, l: Locale) -> mrml::fragment::Fragment<MjBodyChild> {
+    let recipients = html_escape::encode_text(recipients);
+    view!(
+        <>
+            <mj-section background-color="#FFF3CD" padding="0">
+                <mj-column padding="0">
+                    <mj-text font-size="12px" color="#664D03">
+                        <p>{ mrml::text::Text::from(tl!(l, staging.banner, recipients = recipients)).into() }</p>
+                    </mj-text>
+                </mj-column>
+            </mj-section>
+        </>
+    )
+}
@@ -0,0 +1,407 @@
+use std::{collections::BTreeMap, path::PathBuf, sync::Mutex, time::Duration};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use lettre::message::Mailbox;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    extract::Json,
+    queue::{now, write_atomic},
+    send::{send_mail_template, MessageFields, SendTemplateItem},
+    serve::AppState,
+    templates::{digest_entry, Params, TemplateError},
+    Locale,
+};
+
+/// Collecting notifications into periodic digests
+///
+/// | Setting name | Value                                              | Default value       |
+/// | ------------ | -------------------------------------------------- | ------------------- |
+/// | interval     | seconds between digests, `0` to never send them    | `86400` (1 day)     |
+/// | file         | path to the JSON lines file of events not yet sent | unset (memory only) |
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct DigestConfig {
+    pub interval: u64,
+    pub file: Option<PathBuf>,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            interval: 24 * 60 * 60,
+            file: None,
+        }
+    }
+}
+
+/// An event to collect into the recipient's next digest
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct DigestItem {
+    /// The address the digest is sent from
+    from: String,
+    /// The address the digest is sent to
+    to: String,
+    /// Events with the same recipient and key are sent in one digest,
+    /// for example `musicbrainz-edit-notes`
+    digest_key: String,
+    /// Language to render the digest with
+    lang: Option<String>,
+    /// Name to greet the recipient by
+    #[serde(default)]
+    to_name: String,
+    /// Where the recipient can change how they are notified
+    #[serde(default)]
+    settings_url: String,
+    /// Template the event would otherwise be sent with on its own:
+    /// `edit-note`, `follow` or `notification`
+    template_id: String,
+    /// Data to show the event with: the template's params, without
+    /// those which the digest has once for every event, like `to_name`
+    #[serde(default)]
+    params: Value,
+}
+
+/// A recipient and digest key
+type DigestKey = (String, String);
+
+impl DigestItem {
+    fn key(&self) -> DigestKey {
+        (self.to.trim().to_lowercase(), self.digest_key.clone())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DigestError {
+    #[error("Template can't be collected into a digest: {0}")]
+    NotDigestible(String),
+    #[error("Invalid {field} address {address:?}: {source}")]
+    AddressError {
+        field: &'static str,
+        address: String,
+        source: lettre::address::AddressError,
+    },
+    #[error("{0}")]
+    Params(#[from] TemplateError),
+    #[error("Failed to record digest event: {0}")]
+    Record(#[from] std::io::Error),
+}
+
+impl IntoResponse for DigestError {
+    fn into_response(self) -> axum::response::Response {
+        let (code, fields) = match &self {
+            Self::NotDigestible(_) => (ErrorCode::BadRequest, Vec::new()),
+            Self::AddressError { .. } => (ErrorCode::BadAddress, Vec::new()),
+            Self::Params(e) => (ErrorCode::BadParams, e.fields()),
+            Self::Record(_) => (ErrorCode::Internal, Vec::new()),
+        };
+        ApiError::from_error(code, &self)
+            .with_fields(fields)
+            .into_response()
+    }
+}
+
+/// Events waiting to be sent, by recipient and digest key
+///
+/// Events are appended to the file as they arrive. A digest taken to be sent
+/// stays in the file until it has been sent or dropped, so no event is lost
+/// if the service stops during a run.
+#[derive(Debug, Default)]
+pub(crate) struct Digests {
+    file: Option<PathBuf>,
+    pending: Mutex<BTreeMap<DigestKey, Vec<DigestItem>>>,
+    /// Digests taken by the current run, not yet sent
+    sending: Mutex<BTreeMap<DigestKey, Vec<DigestItem>>>,
+    /// Serialises writes of the file, so no event is lost while it's rewritten
+    write: tokio::sync::Mutex<()>,
+    /// Serialises runs, so each digest is only sent by one of them
+    run: tokio::sync::Mutex<()>,
+}
+
+impl Digests {
+    pub(crate) fn open(config: &DigestConfig) -> std::io::Result<Self> {
+        let digests = Self {
+            file: config.file.clone(),
+            ..Default::default()
+        };
+        if let Some(file) = config.file.as_ref().filter(|file| file.exists()) {
+            for line in std::fs::read_to_string(file)?.lines() {
+                if !line.trim().is_empty() {
+                    digests.insert(serde_json::from_str(line)?);
+                }
+            }
+        }
+        Ok(digests)
+    }
+
+    fn insert(&self, item: DigestItem) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry(item.key())
+            .or_default()
+            .push(item);
+    }
+
+    pub(crate) async fn add(&self, item: DigestItem) -> std::io::Result<()> {
+        let _write = self.write.lock().await;
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(&item)?;
+            line.push(b'\n');
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .await?
+                .write_all(&line)
+                .await?;
+        }
+        self.insert(item);
+        Ok(())
+    }
+
+    /// Takes every pending digest to be sent
+    ///
+    /// Each must then be passed to [`Digests::finish`].
+    fn take(&self) -> Vec<(DigestKey, Vec<DigestItem>)> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut sending = self.sending.lock().unwrap();
+        pending
+            .into_iter()
+            .map(|(key, events)| {
+                sending.insert(key.clone(), events.clone());
+                (key, events)
+            })
+            .collect()
+    }
+
+    /// Removes a taken digest from the file, or returns it to the pending
+    /// ones if `keep` is set
+    async fn finish(&self, key: &DigestKey, keep: bool) -> std::io::Result<()> {
+        let _write = self.write.lock().await;
+        let events = self.sending.lock().unwrap().remove(key);
+        if let Some(mut events) = events.filter(|_| keep) {
+            let mut pending = self.pending.lock().unwrap();
+            let newer = pending.entry(key.clone()).or_default();
+            events.append(newer);
+            *newer = events;
+        }
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut lines = Vec::new();
+        {
+            let pending = self.pending.lock().unwrap();
+            let sending = self.sending.lock().unwrap();
+            for item in pending.values().chain(sending.values()).flatten() {
+                serde_json::to_writer(&mut lines, item)?;
+                lines.push(b'\n');
+            }
+        }
+        write_atomic(file, &lines).await
+    }
+}
+
+/// The mail for a recipient's events, addressed as the latest of them
+fn digest_message(events: &[DigestItem]) -> SendTemplateItem {
+    let latest = events.last().expect("digests have at least one event");
+    let entries: Vec<Value> = events
+        .iter()
+        .map(|event| json!({ "template_id": event.template_id, "params": event.params }))
+        .collect();
+    SendTemplateItem::new(
+        "digest".to_owned(),
+        latest.lang.clone(),
+        json!({
+            "to_name": latest.to_name,
+            "settings_url": latest.settings_url,
+            "events": entries,
+        }),
+        MessageFields::new(latest.from.clone(), latest.to.clone()),
+    )
+}
+
+/// Sends every pending digest, returning how many were sent
+///
+/// Digests which fail to send are kept for the next run, unless the failure
+/// is permanent, such as params which no longer match the template.
+pub(crate) async fn send_digests(state: &AppState) -> usize {
+    let digests = &state.digests;
+    let _run = digests.run.lock().await;
+    let mut sent = 0;
+    for (key, events) in digests.take() {
+        let keep = match send_mail_template(state, digest_message(&events)).await {
+            Ok(_) => {
+                counter!("digests_sent_total").increment(1);
+                sent += 1;
+                false
+            }
+            Err(e) if e.code().is_retryable() => {
+                error!("Failed to send digest to {}, keeping it: {e}", key.0);
+                true
+            }
+            Err(e) => {
+                error!("Dropping digest to {}, as it can't be sent: {e}", key.0);
+                counter!("digests_dropped_total").increment(1);
+                false
+            }
+        };
+        if let Err(e) = digests.finish(&key, keep).await {
+            error!("Failed to record digest to {}: {e}", key.0);
+        }
+    }
+    sent
+}
+
+/// Background worker sending digests
+///
+/// Runs are aligned to multiples of the interval since the Unix epoch,
+/// so a daily digest goes out at midnight UTC however often the service
+/// is restarted.
+pub(crate) async fn run(state: AppState, interval: u64) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval - now() % interval)).await;
+        let sent = send_digests(&state).await;
+        info!("Sent {sent} digests");
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/digests",
+    responses(
+        (status = ACCEPTED, description = "The event will be sent in the recipient's next digest"),
+        (status = BAD_REQUEST, description = "The template can't be collected into a digest, or an address is not valid"),
+        (status = UNPROCESSABLE_ENTITY, description = "The params don't match the template"),
+    ),
+    request_body = DigestItem,
+)]
+pub async fn add_digest_route(
+    State(state): State<AppState>,
+    Json(item): Json<DigestItem>,
+) -> Result<StatusCode, DigestError> {
+    let Some(entry) = digest_entry(&item.template_id) else {
+        return Err(DigestError::NotDigestible(item.template_id));
+    };
+    // Checked now, as the digest is sent long after the event is accepted
+    entry(Params::strict(item.params.clone()), Locale::default())?;
+    for (field, address) in [("from", &item.from), ("to", &item.to)] {
+        address
+            .parse::<Mailbox>()
+            .map_err(|source| DigestError::AddressError {
+                field,
+                address: address.clone(),
+                source,
+            })?;
+    }
+    state.digests.add(item).await?;
+    counter!("digest_events_total").increment(1);
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/digests/send",
+    responses(
+        (status = 200, description = "Every pending digest was sent. Returns how many were.", body = usize),
+    )
+)]
+pub async fn send_digests_route(State(state): State<AppState>) -> Json<usize> {
+    Json(send_digests(&state).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::unique_id;
+
+    fn event(to: &str, digest_key: &str) -> DigestItem {
+        DigestItem {
+            from: "noreply@musicbrainz.org".to_owned(),
+            to: to.to_owned(),
+            digest_key: digest_key.to_owned(),
+            lang: None,
+            to_name: "Jade".to_owned(),
+            settings_url: String::new(),
+            template_id: "edit-note".to_owned(),
+            params: json!({ "edit_id": 1, "message": "Hi" }),
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_grouped_and_persisted() {
+        let file = std::env::temp_dir().join(format!("mb-mail-digests-{}", unique_id()));
+        let config = DigestConfig {
+            file: Some(file.clone()),
+            ..Default::default()
+        };
+        let digests = Digests::open(&config).unwrap();
+        digests
+            .add(event("jade@example.org", "edit-notes"))
+            .await
+            .unwrap();
+        digests
+            .add(event("Jade@Example.org", "edit-notes"))
+            .await
+            .unwrap();
+        digests
+            .add(event("jade@example.org", "notifications"))
+            .await
+            .unwrap();
+
+        let reopened = Digests::open(&config).unwrap();
+        let mut sizes: Vec<_> = reopened
+            .take()
+            .iter()
+            .map(|(_, events)| events.len())
+            .collect();
+        sizes.sort();
+        assert_eq!(sizes, [1, 2]);
+        assert!(reopened.take().is_empty());
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn digests_stay_in_the_file_until_finished() {
+        let file = std::env::temp_dir().join(format!("mb-mail-digests-{}", unique_id()));
+        let config = DigestConfig {
+            file: Some(file.clone()),
+            ..Default::default()
+        };
+        let digests = Digests::open(&config).unwrap();
+        digests
+            .add(event("jade@example.org", "edit-notes"))
+            .await
+            .unwrap();
+        digests
+            .add(event("jade@example.org", "notifications"))
+            .await
+            .unwrap();
+        let taken = digests.take();
+        assert_eq!(Digests::open(&config).unwrap().take().len(), 2);
+
+        // Added during the run, so sent with the kept digest next time
+        digests
+            .add(event("jade@example.org", "edit-notes"))
+            .await
+            .unwrap();
+        let notifications = ("jade@example.org".to_owned(), "notifications".to_owned());
+        let edit_notes = ("jade@example.org".to_owned(), "edit-notes".to_owned());
+        assert!(taken.iter().any(|(key, _)| *key == notifications));
+        digests.finish(&notifications, false).await.unwrap();
+        digests.finish(&edit_notes, true).await.unwrap();
+
+        let reopened = Digests::open(&config).unwrap().take();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened[0].0, edit_notes);
+        assert_eq!(reopened[0].1.len(), 2);
+        assert_eq!(digests.take()[0].1.len(), 2);
+        std::fs::remove_file(file).unwrap();
+    }
+}
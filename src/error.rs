@@ -0,0 +1,148 @@
+use std::fmt::{Debug, Display};
+
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// What went wrong with a request, for clients to decide whether to retry it
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request is malformed. Retrying it won't help.
+    BadRequest,
+    /// An address is missing or not valid
+    BadAddress,
+    /// The requested language is not supported
+    BadLanguage,
+    /// The template params don't match what the template expects
+    BadParams,
+    /// The MJML could not be parsed
+    BadMjml,
+    /// The idempotency key was already used for a different request
+    IdempotencyConflict,
+    /// The submitted message is not of the kind expected
+    BadMessage,
+    /// The template does not exist
+    UnknownTemplate,
+    /// What was asked for does not exist, or the feature is not enabled
+    NotFound,
+    /// The request took too long to answer. It can be retried later.
+    Timeout,
+    /// The resource is not in a state the request applies to
+    Conflict,
+    /// The link or resource has expired
+    Gone,
+    /// The route needs a token which wasn't given
+    Unauthorized,
+    /// Sending is not allowed to or from these addresses
+    Forbidden,
+    /// The relay rejected the mail for now. It can be retried later.
+    SmtpTransient,
+    /// The relay rejected the mail, and will do so again
+    SmtpPermanent,
+    /// A service this one relies on failed
+    UpstreamFailed,
+    /// The message could not be rendered
+    RenderFailed,
+    /// Something went wrong within the service
+    Internal,
+}
+
+impl ErrorCode {
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            Self::BadRequest | Self::BadAddress | Self::BadLanguage => StatusCode::BAD_REQUEST,
+            Self::BadParams | Self::BadMjml | Self::BadMessage | Self::IdempotencyConflict => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Self::UnknownTemplate | Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::Gone => StatusCode::GONE,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::SmtpTransient => StatusCode::SERVICE_UNAVAILABLE,
+            Self::SmtpPermanent | Self::UpstreamFailed => StatusCode::BAD_GATEWAY,
+            Self::RenderFailed | Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the same request may succeed if it is sent again later
+    pub(crate) fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Timeout | Self::SmtpTransient | Self::UpstreamFailed | Self::Internal
+        )
+    }
+}
+
+/// The body of every error response
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    /// Whether the same request may succeed if it is sent again later
+    pub retryable: bool,
+    /// A description of the error, for people
+    pub message: String,
+    /// Paths of the params at fault, for `bad_params`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+impl ApiError {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            retryable: code.is_retryable(),
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_fields(self, fields: Vec<String>) -> Self {
+        Self { fields, ..self }
+    }
+
+    /// An error for a classified failure, logged if it's the service's fault
+    pub(crate) fn from_error<E: Display + Debug>(code: ErrorCode, error: &E) -> Self {
+        if code.status().is_server_error() {
+            tracing::error!("{error}: {error:?}");
+        }
+        Self::new(code, error.to_string())
+    }
+
+    pub(crate) fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub(crate) fn internal(error: std::io::Error) -> Self {
+        Self::from_error(ErrorCode::Internal, &error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_snake_case() {
+        let error =
+            serde_json::to_value(ApiError::new(ErrorCode::SmtpTransient, "Try later")).unwrap();
+        assert_eq!(error["code"], "smtp_transient");
+        assert_eq!(error["retryable"], true);
+        assert_eq!(
+            ErrorCode::SmtpTransient.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ErrorCode::BadParams.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::error::{ApiError, ErrorCode};
+
+/// [`axum::Json`], answering bodies which can't be read with an [`ApiError`]
+#[derive(FromRequest, Debug, Clone)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// [`axum::extract::Query`], answering query strings which can't be read with an [`ApiError`]
+#[derive(FromRequestParts, Debug, Clone)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match rejection {
+            // Valid JSON which doesn't match what the route takes
+            JsonRejection::JsonDataError(_) => ErrorCode::BadParams,
+            _ => ErrorCode::BadRequest,
+        };
+        ApiError::new(code, rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(ErrorCode::BadRequest, rejection.body_text())
+    }
+}
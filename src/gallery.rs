@@ -0,0 +1,166 @@
+use axum::response::Html;
+use html_escape::{encode_double_quoted_attribute as attr, encode_text};
+use serde::Deserialize;
+
+use crate::{
+    extract::Query,
+    locale_from_optional_code,
+    render::{render_template, render_text, EngineError},
+    templates::{self, Params},
+    Locale,
+};
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Width {
+    #[default]
+    Desktop,
+    Mobile,
+}
+
+impl Width {
+    fn as_str(self) -> &'static str {
+        match self {
+            Width::Desktop => "desktop",
+            Width::Mobile => "mobile",
+        }
+    }
+
+    /// Width of the frame the HTML is shown in
+    fn frame_width(self) -> &'static str {
+        match self {
+            Width::Desktop => "100%",
+            Width::Mobile => "375px",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GalleryQuery {
+    /// Template to show, the first one if unset
+    template: Option<String>,
+    /// Language to render the template with
+    lang: Option<String>,
+    #[serde(default)]
+    width: Width,
+}
+
+const STYLE: &str = "
+body { margin: 0; font-family: sans-serif; display: flex; height: 100vh; }
+nav { width: 260px; overflow-y: auto; border-right: 1px solid #ddd; padding: 8px; }
+nav a { display: block; padding: 4px 6px; color: inherit; text-decoration: none; border-radius: 4px; }
+nav a.selected { background: #eb743b; color: white; }
+nav small { display: block; opacity: 0.7; }
+main { flex: 1; display: flex; flex-direction: column; min-width: 0; }
+header { display: flex; gap: 16px; align-items: center; padding: 8px 16px; border-bottom: 1px solid #ddd; }
+header a.selected { font-weight: bold; }
+.panes { flex: 1; display: flex; min-height: 0; }
+.pane { flex: 1; overflow: auto; padding: 8px 16px; }
+.pane + .pane { border-left: 1px solid #ddd; }
+iframe { border: 1px solid #ddd; height: 90%; display: block; margin: 0 auto; }
+pre { white-space: pre-wrap; }
+";
+
+/// A link to the gallery, showing a registered template
+fn link(template_id: &str, lang: Locale, width: Width) -> String {
+    format!(
+        "/gallery?template={template_id}&lang={}&width={}",
+        lang.as_str(),
+        width.as_str()
+    )
+}
+
+/// A page showing how templates render with their example params
+pub async fn gallery_route(
+    Query(GalleryQuery {
+        template,
+        lang,
+        width,
+    }): Query<GalleryQuery>,
+) -> Result<Html<String>, EngineError> {
+    let lang = locale_from_optional_code(lang)?;
+    let template_id = template.unwrap_or_else(|| templates::all()[0].id.to_owned());
+    let params = templates::example(&template_id).unwrap_or_default();
+    let (html, subject) =
+        render_template(template_id.clone(), Params::strict(params), lang).await?;
+    let text = render_text(&html).await?;
+
+    let mut nav = String::new();
+    for template in templates::all() {
+        let brand = template.brand.map_or("all sites", |brand| brand.site());
+        nav += &format!(
+            "<a href=\"{href}\"{class} title=\"{description}\">{id}<small>{brand}</small></a>",
+            href = attr(&link(template.id, lang, width)),
+            class = if template.id == template_id {
+                " class=\"selected\""
+            } else {
+                ""
+            },
+            description = attr(template.description),
+            id = encode_text(template.id),
+        );
+    }
+
+    let mut locales = String::new();
+    for locale in Locale::VALUES.iter() {
+        locales += &format!(
+            "<option value=\"{code}\"{selected}>{code}</option>",
+            code = locale.as_str(),
+            selected = if locale.as_str() == lang.as_str() {
+                " selected"
+            } else {
+                ""
+            },
+        );
+    }
+
+    let mut widths = String::new();
+    for option in [Width::Desktop, Width::Mobile] {
+        widths += &format!(
+            "<a href=\"{href}\"{class}>{name}</a> ",
+            href = attr(&link(&template_id, lang, option)),
+            class = if option == width {
+                " class=\"selected\""
+            } else {
+                ""
+            },
+            name = option.as_str(),
+        );
+    }
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{id} - Template gallery</title>
+<style>{STYLE}</style>
+</head>
+<body>
+<nav>{nav}</nav>
+<main>
+<header>
+<form action=\"/gallery\">
+<input type=\"hidden\" name=\"template\" value=\"{id}\">
+<input type=\"hidden\" name=\"width\" value=\"{width}\">
+<select name=\"lang\" onchange=\"this.form.submit()\">{locales}</select>
+<noscript><button>Show</button></noscript>
+</form>
+<span>{widths}</span>
+<strong>Subject: {subject}</strong>
+</header>
+<div class=\"panes\">
+<div class=\"pane\"><iframe style=\"width: {frame_width}\" srcdoc=\"{html}\"></iframe></div>
+<div class=\"pane\"><pre>{text}</pre></div>
+</div>
+</main>
+</body>
+</html>",
+        id = attr(&template_id),
+        width = width.as_str(),
+        subject = encode_text(&subject.unwrap_or_default()),
+        frame_width = width.frame_width(),
+        html = attr(&html),
+        text = encode_text(&text),
+    )))
+}
@@ -0,0 +1,223 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use metrics::counter;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+/// How long results are remembered to detect repeated requests
+///
+/// | Setting name | Value                                  | Default value    |
+/// | ------------ | -------------------------------------- | ---------------- |
+/// | window       | seconds, `0` to disable                | `86400` (1 day)  |
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct IdempotencyConfig {
+    pub window: u64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            window: 24 * 60 * 60,
+        }
+    }
+}
+
+/// How often expired results are cleared out
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Identifies repeats of a request
+#[derive(Debug, Clone)]
+pub(crate) struct IdempotencyKey {
+    /// The route and the key the client gave, which the cache is keyed on
+    scope: (&'static str, String),
+    /// A hash of the request body, which repeats must match
+    body: [u8; 32],
+}
+
+impl IdempotencyKey {
+    pub(crate) fn new(route: &'static str, key: String, body: &impl Serialize) -> Self {
+        let body = serde_json::to_vec(body).expect("request bodies can be serialized");
+        Self {
+            scope: (route, key),
+            body: Sha256::digest(body).into(),
+        }
+    }
+}
+
+/// A key was used again with a different request
+#[derive(Debug, thiserror::Error)]
+#[error("The idempotency key was already used for a different request")]
+pub(crate) struct IdempotencyMismatch;
+
+#[derive(Debug)]
+struct Slot<T> {
+    created: Instant,
+    body: [u8; 32],
+    result: Arc<OnceCell<T>>,
+}
+
+impl<T> Slot<T> {
+    fn new(body: [u8; 32]) -> Self {
+        Self {
+            created: Instant::now(),
+            body,
+            result: Default::default(),
+        }
+    }
+}
+
+/// Remembers the results of recent requests by route and key
+///
+/// A request repeated with the same key within the window gets the
+/// original result back, instead of being run again. If the original
+/// request is still running, the repeat waits for it to finish.
+/// Failed requests are not remembered, so they can be retried. A key
+/// reused with a different body, once the original has succeeded, fails
+/// rather than getting a result which doesn't belong to it.
+#[derive(Debug)]
+pub(crate) struct Idempotency<T> {
+    window: Duration,
+    slots: DashMap<(&'static str, String), Slot<T>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl<T> Default for Idempotency<T> {
+    fn default() -> Self {
+        Self::new(&IdempotencyConfig::default())
+    }
+}
+
+impl<T: Clone> Idempotency<T> {
+    pub(crate) fn new(config: &IdempotencyConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window),
+            slots: DashMap::new(),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn prune(&self) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if last_prune.elapsed() > PRUNE_INTERVAL {
+            self.slots
+                .retain(|_, slot| slot.created.elapsed() <= self.window);
+            *last_prune = Instant::now();
+        }
+    }
+
+    /// Runs `f`, unless a request with the same key has already succeeded
+    pub(crate) async fn run<E, F, Fut>(&self, key: Option<IdempotencyKey>, f: F) -> Result<T, E>
+    where
+        E: From<IdempotencyMismatch>,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let Some(IdempotencyKey { scope, body }) = key.filter(|_| !self.window.is_zero()) else {
+            return f().await;
+        };
+        self.prune();
+
+        let cell = {
+            let mut slot = self.slots.entry(scope).or_insert_with(|| Slot::new(body));
+            if slot.created.elapsed() > self.window {
+                *slot = Slot::new(body);
+            } else if slot.body != body {
+                if slot.result.initialized() {
+                    return Err(IdempotencyMismatch.into());
+                }
+                // The original failed, or hasn't finished, so this takes its place
+                *slot = Slot::new(body);
+            }
+            slot.result.clone()
+        };
+
+        let mut ran = false;
+        let result = cell
+            .get_or_try_init(|| {
+                ran = true;
+                f()
+            })
+            .await?;
+        if !ran {
+            counter!("mails_deduplicated_total").increment(1);
+        }
+        Ok(result.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum TestError {
+        Failed,
+        Mismatch,
+    }
+
+    impl From<IdempotencyMismatch> for TestError {
+        fn from(_: IdempotencyMismatch) -> Self {
+            Self::Mismatch
+        }
+    }
+
+    fn key(route: &'static str, key: &str, body: u32) -> Option<IdempotencyKey> {
+        Some(IdempotencyKey::new(route, key.to_owned(), &body))
+    }
+
+    #[tokio::test]
+    async fn repeated_key_returns_first_result() {
+        let cache = Idempotency::<u32>::default();
+        let first = cache.run(key("send", "a", 0), || async { Ok::<_, TestError>(1) });
+        assert_eq!(first.await, Ok(1));
+        let second = cache.run(key("send", "a", 0), || async { Ok::<_, TestError>(2) });
+        assert_eq!(second.await, Ok(1));
+        let other = cache.run(key("send", "b", 0), || async { Ok::<_, TestError>(3) });
+        assert_eq!(other.await, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_to_the_route() {
+        let cache = Idempotency::<u32>::default();
+        let first = cache.run(key("send", "a", 0), || async { Ok::<_, TestError>(1) });
+        assert_eq!(first.await, Ok(1));
+        let other = cache.run(key("send_mjml", "a", 0), || async { Ok::<_, TestError>(2) });
+        assert_eq!(other.await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn reused_key_with_another_body_fails() {
+        let cache = Idempotency::<u32>::default();
+        let first = cache.run(key("send", "a", 0), || async { Ok::<_, TestError>(1) });
+        assert_eq!(first.await, Ok(1));
+        let second = cache.run(key("send", "a", 1), || async { Ok::<_, TestError>(2) });
+        assert_eq!(second.await, Err(TestError::Mismatch));
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_remembered() {
+        let cache = Idempotency::<u32>::default();
+        let first = cache.run(key("send", "a", 0), || async {
+            Err::<u32, _>(TestError::Failed)
+        });
+        assert_eq!(first.await, Err(TestError::Failed));
+        let second = cache.run(key("send", "a", 1), || async { Ok::<_, TestError>(2) });
+        assert_eq!(second.await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_key_always_run() {
+        let cache = Idempotency::<u32>::default();
+        let first = cache.run(None, || async { Ok::<_, TestError>(1) });
+        assert_eq!(first.await, Ok(1));
+        let second = cache.run(None, || async { Ok::<_, TestError>(2) });
+        assert_eq!(second.await, Ok(2));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use dashmap::DashMap;
+use futures::prelude::*;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    queue::unique_id,
+    send::{send_bulk_item, BulkItemResponse, SendTemplateItem, PAR_SENDERS},
+    serve::AppState,
+};
+
+/// How long a finished job's results are kept around for polling
+const JOB_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Cancelled,
+}
+
+/// Progress of a background bulk send
+#[derive(Serialize, ToSchema, Debug)]
+pub struct JobStatus {
+    id: String,
+    state: JobState,
+    total: usize,
+    /// Items which were sent or queued
+    succeeded: usize,
+    /// Items which failed to send
+    failed: usize,
+    /// Items which have not been attempted yet
+    pending: usize,
+    /// Results in the same order as the submitted items.
+    /// Items which have not been attempted yet are `null`.
+    results: Vec<Option<BulkItemResponse>>,
+}
+
+/// A bulk send running in the background
+#[derive(Debug)]
+pub(crate) struct Job {
+    id: String,
+    results: Mutex<Vec<Option<BulkItemResponse>>>,
+    cancelled: AtomicBool,
+    finished: Mutex<Option<Instant>>,
+}
+
+impl Job {
+    pub(crate) fn status(&self) -> JobStatus {
+        let results = self.results.lock().unwrap().clone();
+        let failed = results
+            .iter()
+            .filter(|r| r.as_ref().is_some_and(BulkItemResponse::is_error))
+            .count();
+        let pending = results.iter().filter(|r| r.is_none()).count();
+        let state = if self.cancelled.load(Ordering::Relaxed) {
+            JobState::Cancelled
+        } else if self.finished.lock().unwrap().is_some() {
+            JobState::Completed
+        } else {
+            JobState::Running
+        };
+        JobStatus {
+            id: self.id.clone(),
+            state,
+            total: results.len(),
+            succeeded: results.len() - failed - pending,
+            failed,
+            pending,
+            results,
+        }
+    }
+
+    /// Stops any items which haven't started sending yet
+    ///
+    /// Returns whether the job was still running.
+    fn cancel(&self) -> bool {
+        // Held so the job can't finish in between
+        let finished = self.finished.lock().unwrap();
+        if finished.is_some() {
+            return false;
+        }
+        self.cancelled.store(true, Ordering::Relaxed);
+        true
+    }
+
+    fn expired(&self) -> bool {
+        self.finished
+            .lock()
+            .unwrap()
+            .is_some_and(|finished| finished.elapsed() > JOB_RETENTION)
+    }
+}
+
+/// All background bulk sends known to this process
+#[derive(Debug, Default)]
+pub(crate) struct Jobs {
+    jobs: DashMap<String, Arc<Job>>,
+}
+
+impl Jobs {
+    pub(crate) fn start(&self, state: AppState, items: Vec<SendTemplateItem>) -> Arc<Job> {
+        self.jobs.retain(|_, job| !job.expired());
+
+        let job = Arc::new(Job {
+            id: unique_id(),
+            results: Mutex::new(vec![None; items.len()]),
+            cancelled: AtomicBool::new(false),
+            finished: Mutex::new(None),
+        });
+        self.jobs.insert(job.id.clone(), job.clone());
+        tokio::spawn(run(job.clone(), state, items));
+        job
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.get(id).map(|job| job.clone())
+    }
+}
+
+#[tracing::instrument(skip(state, items), fields(id = %job.id))]
+async fn run(job: Arc<Job>, state: AppState, items: Vec<SendTemplateItem>) {
+    stream::iter(items)
+        .enumerate()
+        .for_each_concurrent(PAR_SENDERS, |(i, item)| {
+            let job = &job;
+            let state = &state;
+            async move {
+                if job.cancelled.load(Ordering::Relaxed) {
+                    return;
+                }
+                let res = send_bulk_item(state, i, item).await;
+                job.results.lock().unwrap()[i] = Some(res);
+            }
+        })
+        .await;
+    *job.finished.lock().unwrap() = Some(Instant::now());
+    tracing::info!("Bulk job finished");
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}",
+    responses(
+        (status = 200, description = "Current progress of the job", body = JobStatus),
+        (status = NOT_FOUND, description = "Job was not found")
+    ),
+    params(
+        ("job_id" = String, Path, description = "ID returned when starting a background bulk send"),
+    )
+)]
+pub async fn get_job_route(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Result<Json<JobStatus>, ApiError> {
+    let job = state
+        .jobs
+        .get(&job_id)
+        .ok_or_else(|| ApiError::not_found(format!("Job not found: {job_id}")))?;
+    Ok(Json(job.status()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/jobs/{job_id}",
+    responses(
+        (status = 200, description = "Job cancelled. Items already sent are not affected.", body = JobStatus),
+        (status = NOT_FOUND, description = "Job was not found"),
+        (status = CONFLICT, description = "Job has already finished")
+    ),
+    params(
+        ("job_id" = String, Path, description = "ID returned when starting a background bulk send"),
+    )
+)]
+pub async fn cancel_job_route(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Result<Json<JobStatus>, ApiError> {
+    let job = state
+        .jobs
+        .get(&job_id)
+        .ok_or_else(|| ApiError::not_found(format!("Job not found: {job_id}")))?;
+    if !job.cancel() {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("Job has already finished: {job_id}"),
+        ));
+    }
+    Ok(Json(job.status()))
+}
@@ -4,11 +4,30 @@ use render::EngineError;
 use tracing::warn;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod admin;
+mod attachments;
+mod bounce;
+mod brand;
 mod components;
+mod digest;
+mod dkim;
+mod error;
+mod extract;
+mod gallery;
+mod idempotency;
+mod jobs;
+mod preview;
+mod queue;
+mod ratelimit;
+mod relays;
 mod render;
 mod send;
 mod serve;
+mod staging;
+mod suppression;
 mod templates;
+mod transport;
+mod unsubscribe;
 
 mf1::load_locales!();
 
@@ -20,7 +39,38 @@ pub(crate) struct Settings {
     #[serde(default)]
     pub listen: serve::ListenerConfig,
     #[serde(default)]
+    transport: transport::TransportConfig,
+    #[serde(default)]
     smtp: serve::SmtpMailerConfig,
+    /// Relays to fail over between, in order of preference.
+    /// If empty, only the `smtp` relay is used.
+    #[serde(default)]
+    relays: Vec<serve::SmtpMailerConfig>,
+    #[serde(default)]
+    failover: relays::FailoverConfig,
+    #[serde(default)]
+    ratelimit: ratelimit::RateLimitConfig,
+    #[serde(default)]
+    queue: queue::QueueConfig,
+    #[serde(default)]
+    idempotency: idempotency::IdempotencyConfig,
+    /// DKIM keys by From domain
+    #[serde(default)]
+    dkim: std::collections::HashMap<String, dkim::DkimKeyConfig>,
+    #[serde(default)]
+    unsubscribe: unsubscribe::UnsubscribeConfig,
+    #[serde(default)]
+    admin: admin::AdminConfig,
+    #[serde(default)]
+    suppression: suppression::SuppressionConfig,
+    #[serde(default)]
+    bounce: bounce::BounceConfig,
+    #[serde(default)]
+    digest: digest::DigestConfig,
+    #[serde(default)]
+    staging: staging::StagingConfig,
+    #[serde(default)]
+    brand: brand::BrandConfig,
 }
 
 fn locale_from_optional_code(lang: Option<String>) -> Result<Locale, EngineError> {
@@ -63,7 +113,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ..sentry::ClientOptions::default()
     });
 
-    let config = Config::builder()
+    // Settings which can't be expressed as environment variables, like lists
+    // of relays, can be given in a TOML file. Environment variables take priority.
+    let mut config = Config::builder();
+    if let Ok(path) = std::env::var("APP_CONFIG") {
+        config = config.add_source(config::File::new(&path, config::FileFormat::Toml));
+    }
+    let config = config
         .add_source(
             config::Environment::with_prefix("APP")
                 .try_parsing(true)
@@ -122,6 +178,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     };
 
-    rt.block_on(serve::serve(settings.listen, settings.smtp));
+    rt.block_on(serve::serve(settings));
     Ok(())
 }
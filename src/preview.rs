@@ -0,0 +1,158 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use lettre::Message;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    extract::{Json, Query},
+    send::{build_mjml_message, build_template_message, SendError, SendMjmlItem, SendTemplateItem},
+    serve::AppState,
+};
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PreviewFormat {
+    /// The raw message, as `message/rfc822`
+    #[default]
+    Rfc822,
+    /// A JSON breakdown of the envelope and headers, along with the raw message
+    Json,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct PreviewQuery {
+    /// How to return the built message
+    #[serde(default)]
+    format: PreviewFormat,
+}
+
+/// A header of a built message
+#[derive(Serialize, ToSchema, Debug)]
+pub struct PreviewHeader {
+    name: String,
+    /// The value as it would be sent, unfolded but still encoded
+    value: String,
+}
+
+/// A message which was built but not sent
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Preview {
+    /// The envelope sender, given to the relay in `MAIL FROM`
+    envelope_from: Option<String>,
+    /// The envelope recipients, given to the relay in `RCPT TO`
+    envelope_to: Vec<String>,
+    /// Envelope recipients on the suppression list, who would be left out when sending
+    suppressed: Vec<String>,
+    /// Every header of the message, in order
+    headers: Vec<PreviewHeader>,
+    /// The complete message
+    message: String,
+}
+
+/// Splits the header block of a formatted message into its fields
+fn headers(raw: &str) -> Vec<PreviewHeader> {
+    let block = raw.split("\r\n\r\n").next().unwrap_or_default();
+    let mut headers: Vec<PreviewHeader> = Vec::new();
+    for line in block.split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some(header) = headers.last_mut() {
+                header.value.push(' ');
+                header.value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push(PreviewHeader {
+                name: name.to_owned(),
+                value: value.trim().to_owned(),
+            });
+        }
+    }
+    headers
+}
+
+/// Signs a built message as it would be for sending, and returns it in the requested format
+fn preview(state: &AppState, mut email: Message, format: PreviewFormat) -> Response {
+    state.dkim.sign(&mut email);
+    let raw = email.formatted();
+    match format {
+        PreviewFormat::Rfc822 => ([(header::CONTENT_TYPE, "message/rfc822")], raw).into_response(),
+        PreviewFormat::Json => {
+            let envelope = email.envelope();
+            let message = String::from_utf8_lossy(&raw).into_owned();
+            Json(Preview {
+                envelope_from: envelope.from().map(ToString::to_string),
+                envelope_to: envelope.to().iter().map(ToString::to_string).collect(),
+                suppressed: envelope
+                    .to()
+                    .iter()
+                    .filter(|address| state.suppressions.is_suppressed(address.as_ref()))
+                    .map(ToString::to_string)
+                    .collect(),
+                headers: headers(&message),
+                message,
+            })
+            .into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/preview_single",
+    responses(
+        (status = 200, description = "The message which would be sent", content(
+            (Preview = "application/json"),
+            (String = "message/rfc822")
+        )),
+        (status = NOT_FOUND, description = "Template was not found")
+    ),
+    params(PreviewQuery),
+    request_body = SendTemplateItem,
+)]
+pub async fn preview_mail_route(
+    State(state): State<AppState>,
+    Query(PreviewQuery { format }): Query<PreviewQuery>,
+    Json(item): Json<SendTemplateItem>,
+) -> Result<Response, SendError> {
+    let email = build_template_message(&state, item).await?;
+    Ok(preview(&state, email, format))
+}
+
+#[utoipa::path(
+    post,
+    path = "/preview_single_mjml",
+    responses(
+        (status = 200, description = "The message which would be sent", content(
+            (Preview = "application/json"),
+            (String = "message/rfc822")
+        )),
+    ),
+    params(PreviewQuery),
+    request_body = SendMjmlItem,
+)]
+pub async fn preview_mail_mjml_route(
+    State(state): State<AppState>,
+    Query(PreviewQuery { format }): Query<PreviewQuery>,
+    Json(item): Json<SendMjmlItem>,
+) -> Result<Response, SendError> {
+    let email = build_mjml_message(&state, item).await?;
+    Ok(preview(&state, email, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folded_headers_are_unfolded() {
+        let raw = "Subject: A long\r\n subject line\r\nTo: jade@example.org\r\n\r\nBody: text";
+        let headers = headers(raw);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, "Subject");
+        assert_eq!(headers[0].value, "A long subject line");
+        assert_eq!(headers[1].value, "jade@example.org");
+    }
+}
@@ -0,0 +1,569 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    Json,
+};
+use lettre::{address::Envelope, Message};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs;
+use tracing::{debug, error, warn};
+use utoipa::ToSchema;
+
+use crate::{error::ApiError, send::deliver, serve::AppState};
+
+/// Durable outbound queue
+///
+/// When `dir` is set, send routes write fully built messages
+/// into the spool directory and return immediately. A background
+/// worker delivers them, retrying with exponential backoff.
+///
+/// | Setting name | Value                                          | Default value |
+/// | ------------ | ---------------------------------------------- | ------------- |
+/// | dir          | path to the spool directory                    | unset (off)   |
+/// | attempts     | delivery attempts before dead-lettering        | `8`           |
+/// | backoff      | base retry delay in seconds, doubled each time | `30`          |
+/// | interval     | seconds between scans for due messages         | `5`           |
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct QueueConfig {
+    pub dir: Option<PathBuf>,
+    pub attempts: u32,
+    pub backoff: u64,
+    pub interval: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            attempts: 8,
+            backoff: 30,
+            interval: 5,
+        }
+    }
+}
+
+/// Longest delay between two attempts, whatever the backoff
+const MAX_BACKOFF: u64 = 6 * 60 * 60;
+
+/// Metadata stored next to each spooled message
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct QueuedMail {
+    pub id: String,
+    pub envelope: Envelope,
+    pub attempts: u32,
+    /// Unix timestamp (seconds) of the next delivery attempt
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+    /// Unix timestamp (seconds) the mail was scheduled to be sent at, if any
+    #[serde(default)]
+    pub send_at: Option<u64>,
+}
+
+impl QueuedMail {
+    /// Whether the mail is waiting for its scheduled time, and can still be cancelled
+    fn is_scheduled(&self) -> bool {
+        self.attempts == 0 && self.send_at.is_some_and(|send_at| send_at > now())
+    }
+}
+
+/// A directory of messages waiting to be delivered
+///
+/// Each message is stored as a raw `<id>.eml` file with a `<id>.json`
+/// metadata file alongside it. The metadata is written last, so a
+/// message is only picked up once it has been fully written.
+/// Messages that can't be delivered are moved into `dead/`.
+#[derive(Debug)]
+pub(crate) struct Spool {
+    pending: PathBuf,
+    dead: PathBuf,
+    attempts: u32,
+    backoff: u64,
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Generates an identifier unique to this process
+pub(crate) fn unique_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{nanos:x}-{:x}-{:x}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Write a file so that readers never see a partial version of it
+pub(crate) async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await
+}
+
+impl Spool {
+    pub(crate) async fn open(config: &QueueConfig) -> std::io::Result<Option<Self>> {
+        let Some(dir) = &config.dir else {
+            return Ok(None);
+        };
+        let spool = Self {
+            pending: dir.join("pending"),
+            dead: dir.join("dead"),
+            attempts: config.attempts.max(1),
+            backoff: config.backoff,
+        };
+        fs::create_dir_all(&spool.pending).await?;
+        fs::create_dir_all(&spool.dead).await?;
+        Ok(Some(spool))
+    }
+
+    fn paths(dir: &Path, id: &str) -> (PathBuf, PathBuf) {
+        (
+            dir.join(format!("{id}.json")),
+            dir.join(format!("{id}.eml")),
+        )
+    }
+
+    /// Adds a message to the spool, returning its queue ID
+    ///
+    /// The first attempt is made at `send_at` if given, otherwise straight away.
+    pub(crate) async fn enqueue(
+        &self,
+        envelope: &Envelope,
+        message: &Message,
+        send_at: Option<u64>,
+    ) -> std::io::Result<String> {
+        let mail = QueuedMail {
+            id: unique_id(),
+            envelope: envelope.clone(),
+            attempts: 0,
+            next_attempt: send_at.unwrap_or_else(now),
+            last_error: None,
+            send_at,
+        };
+        let (meta, eml) = Self::paths(&self.pending, &mail.id);
+        write_atomic(&eml, &message.formatted()).await?;
+        write_atomic(&meta, &serde_json::to_vec(&mail)?).await?;
+        counter!("mails_queued_total").increment(1);
+        Ok(mail.id)
+    }
+
+    /// Every message waiting to be delivered
+    ///
+    /// Entries which can't be read are skipped, so one bad entry can't hold up the others.
+    async fn pending(&self) -> std::io::Result<Vec<QueuedMail>> {
+        let mut pending = Vec::new();
+        let mut entries = fs::read_dir(&self.pending).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let meta = match fs::read(&path).await {
+                Ok(meta) => meta,
+                // Delivered or cancelled since the directory was listed
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("Ignoring unreadable spool entry {}: {e}", path.display());
+                    continue;
+                }
+            };
+            match serde_json::from_slice::<QueuedMail>(&meta) {
+                Ok(mail) => pending.push(mail),
+                Err(e) => warn!("Ignoring unreadable spool entry {}: {e}", path.display()),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// All pending messages whose next attempt is due
+    pub(crate) async fn due(&self) -> std::io::Result<Vec<QueuedMail>> {
+        let now = now();
+        let mut due = self.pending().await?;
+        due.retain(|mail| mail.next_attempt <= now);
+        Ok(due)
+    }
+
+    /// Messages still waiting for their scheduled time, soonest first
+    pub(crate) async fn scheduled(&self) -> std::io::Result<Vec<QueuedMail>> {
+        let mut scheduled = self.pending().await?;
+        scheduled.retain(QueuedMail::is_scheduled);
+        scheduled.sort_by_key(|mail| mail.next_attempt);
+        Ok(scheduled)
+    }
+
+    /// Removes a message which hasn't reached its scheduled time yet
+    pub(crate) async fn cancel(&self, id: &str) -> std::io::Result<Option<QueuedMail>> {
+        // IDs are used in paths, so must not be able to point outside the spool
+        if !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Ok(None);
+        }
+        let (meta, _) = Self::paths(&self.pending, id);
+        let mail: QueuedMail = match fs::read(&meta).await {
+            Ok(meta) => serde_json::from_slice(&meta)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !mail.is_scheduled() {
+            return Ok(None);
+        }
+        self.complete(id).await?;
+        counter!("mails_schedule_cancelled_total").increment(1);
+        Ok(Some(mail))
+    }
+
+    pub(crate) async fn message(&self, id: &str) -> std::io::Result<Vec<u8>> {
+        fs::read(Self::paths(&self.pending, id).1).await
+    }
+
+    /// Removes a delivered message from the spool
+    pub(crate) async fn complete(&self, id: &str) -> std::io::Result<()> {
+        let (meta, eml) = Self::paths(&self.pending, id);
+        fs::remove_file(meta).await?;
+        fs::remove_file(eml).await
+    }
+
+    /// Records a failed delivery attempt
+    ///
+    /// The message is rescheduled with exponential backoff, or moved
+    /// to the dead-letter directory if the failure was permanent or
+    /// it has run out of attempts.
+    pub(crate) async fn fail(
+        &self,
+        mut mail: QueuedMail,
+        error: String,
+        permanent: bool,
+    ) -> std::io::Result<()> {
+        mail.attempts += 1;
+        mail.last_error = Some(error);
+        let (meta, eml) = Self::paths(&self.pending, &mail.id);
+        if permanent || mail.attempts >= self.attempts {
+            let (dead_meta, dead_eml) = Self::paths(&self.dead, &mail.id);
+            write_atomic(&dead_meta, &serde_json::to_vec(&mail)?).await?;
+            fs::rename(eml, dead_eml).await?;
+            fs::remove_file(meta).await?;
+            counter!("mails_dead_lettered_total").increment(1);
+            error!(
+                "Gave up delivering {} after {} attempts: {:?}",
+                mail.id, mail.attempts, mail.last_error
+            );
+        } else {
+            mail.next_attempt = now() + self.retry_delay(mail.attempts);
+            write_atomic(&meta, &serde_json::to_vec(&mail)?).await?;
+            counter!("mails_retried_total").increment(1);
+        }
+        Ok(())
+    }
+
+    /// Moves a message whose `.eml` is missing into the dead-letter directory
+    ///
+    /// This is left behind if the service stops part way through
+    /// dead-lettering a message. If the metadata is gone too, the message
+    /// was delivered or cancelled in the meantime, and nothing is done.
+    async fn dead_letter_orphan(&self, mut mail: QueuedMail) -> std::io::Result<()> {
+        let (meta, _) = Self::paths(&self.pending, &mail.id);
+        if !fs::try_exists(&meta).await? {
+            return Ok(());
+        }
+        let (dead_meta, _) = Self::paths(&self.dead, &mail.id);
+        if !fs::try_exists(&dead_meta).await? {
+            mail.last_error = Some("The message file is missing".to_owned());
+            write_atomic(&dead_meta, &serde_json::to_vec(&mail)?).await?;
+            counter!("mails_dead_lettered_total").increment(1);
+        }
+        fs::remove_file(meta).await?;
+        error!(
+            "Dead-lettered queued mail {}, as its message is missing",
+            mail.id
+        );
+        Ok(())
+    }
+
+    fn retry_delay(&self, attempts: u32) -> u64 {
+        self.backoff
+            .saturating_mul(1u64 << attempts.saturating_sub(1).min(32))
+            .min(MAX_BACKOFF)
+    }
+}
+
+async fn deliver_due(spool: &Spool, state: &AppState) -> std::io::Result<()> {
+    for mail in spool.due().await? {
+        let id = mail.id.clone();
+        // A problem with one message mustn't hold up the rest of the queue
+        if let Err(e) = deliver_queued(spool, state, mail).await {
+            error!("Failed to process queued mail {id}: {e}");
+        }
+    }
+    Ok(())
+}
+
+async fn deliver_queued(spool: &Spool, state: &AppState, mail: QueuedMail) -> std::io::Result<()> {
+    // Recipients may have been suppressed while the mail was queued
+    let Some(envelope) = state.suppressions.allowed(&mail.envelope) else {
+        debug!(
+            "Dropping queued mail {}, as its recipients are suppressed",
+            mail.id
+        );
+        return spool.complete(&mail.id).await;
+    };
+    let raw = match spool.message(&mail.id).await {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == ErrorKind::NotFound => return spool.dead_letter_orphan(mail).await,
+        Err(e) => return Err(e),
+    };
+    match deliver(state, &envelope, &raw).await {
+        Ok(res) if res.is_positive() => {
+            debug!("Delivered queued mail {}", mail.id);
+            spool.complete(&mail.id).await?;
+            counter!("mails_sent_total").increment(1);
+        }
+        Ok(res) => {
+            let message = res.message().collect::<Vec<_>>().join("\n");
+            spool.fail(mail, message, false).await?;
+        }
+        Err(e) => {
+            let permanent = e.is_permanent();
+            spool.fail(mail, e.to_string(), permanent).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Mail waiting to be sent at a later time
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ScheduledMail {
+    id: String,
+    /// When the mail will be sent, in RFC 3339 format
+    send_at: String,
+    /// Envelope recipients
+    recipients: Vec<String>,
+}
+
+/// Formats a Unix timestamp as RFC 3339
+pub(crate) fn rfc3339(timestamp: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp.try_into().unwrap_or(i64::MAX))
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+impl From<QueuedMail> for ScheduledMail {
+    fn from(mail: QueuedMail) -> Self {
+        Self {
+            id: mail.id,
+            send_at: rfc3339(mail.send_at.unwrap_or(mail.next_attempt)),
+            recipients: mail.envelope.to().iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+fn spool(state: &AppState) -> Result<&Spool, ApiError> {
+    state.spool.as_deref().ok_or_else(|| {
+        ApiError::not_found("Scheduled delivery needs the outbound queue to be enabled")
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/scheduled",
+    responses(
+        (status = 200, description = "Mail waiting to be sent, soonest first", body = [ScheduledMail]),
+        (status = NOT_FOUND, description = "The outbound queue is not enabled")
+    )
+)]
+pub async fn list_scheduled_route(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ScheduledMail>>, ApiError> {
+    let scheduled = spool(&state)?
+        .scheduled()
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(Json(scheduled.into_iter().map(Into::into).collect()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/scheduled/{id}",
+    responses(
+        (status = 200, description = "The mail was cancelled", body = ScheduledMail),
+        (status = NOT_FOUND, description = "No mail is scheduled with this ID. It may already have been sent.")
+    ),
+    params(
+        ("id" = String, Path, description = "ID returned when the mail was scheduled"),
+    )
+)]
+pub async fn cancel_scheduled_route(
+    State(state): State<AppState>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<ScheduledMail>, ApiError> {
+    spool(&state)?
+        .cancel(&id)
+        .await
+        .map_err(ApiError::internal)?
+        .map(|mail| Json(mail.into()))
+        .ok_or_else(|| ApiError::not_found(format!("No mail is scheduled with ID {id}")))
+}
+
+/// Background worker delivering spooled mail
+pub(crate) async fn run(spool: Arc<Spool>, state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = deliver_due(&spool, &state).await {
+            error!("Failed to process mail queue: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_spool(name: &str) -> Spool {
+        let dir = std::env::temp_dir().join(format!("mb-mail-{name}-{}", unique_id()));
+        Spool::open(&QueueConfig {
+            dir: Some(dir),
+            attempts: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    fn remove_spool(spool: Spool) {
+        std::fs::remove_dir_all(spool.pending.parent().unwrap()).unwrap();
+    }
+
+    fn test_message() -> Message {
+        Message::builder()
+            .from("noreply@musicbrainz.org".parse().unwrap())
+            .to("jade@example.org".parse().unwrap())
+            .body(String::from("Hello"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn enqueued_mail_is_due() {
+        let spool = test_spool("due").await;
+        let message = test_message();
+        let id = spool
+            .enqueue(message.envelope(), &message, None)
+            .await
+            .unwrap();
+        let due = spool.due().await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, id);
+        assert!(String::from_utf8(spool.message(&id).await.unwrap())
+            .unwrap()
+            .contains("jade@example.org"));
+        remove_spool(spool);
+    }
+
+    #[tokio::test]
+    async fn failed_mail_is_retried_then_dead_lettered() {
+        let spool = test_spool("dead").await;
+        let message = test_message();
+        let id = spool
+            .enqueue(message.envelope(), &message, None)
+            .await
+            .unwrap();
+
+        let mail = spool.due().await.unwrap().pop().unwrap();
+        spool
+            .fail(mail, "421 try later".into(), false)
+            .await
+            .unwrap();
+        assert!(spool.due().await.unwrap().is_empty());
+        assert!(spool.pending.join(format!("{id}.eml")).exists());
+
+        let meta = fs::read(spool.pending.join(format!("{id}.json"))).await;
+        let mail: QueuedMail = serde_json::from_slice(&meta.unwrap()).unwrap();
+        spool
+            .fail(mail, "421 try later".into(), false)
+            .await
+            .unwrap();
+        assert!(!spool.pending.join(format!("{id}.eml")).exists());
+        assert!(spool.dead.join(format!("{id}.eml")).exists());
+        remove_spool(spool);
+    }
+
+    #[tokio::test]
+    async fn mail_without_message_is_dead_lettered() {
+        let spool = test_spool("orphan").await;
+        let message = test_message();
+        let orphan = spool
+            .enqueue(message.envelope(), &message, None)
+            .await
+            .unwrap();
+        let other = spool
+            .enqueue(message.envelope(), &message, None)
+            .await
+            .unwrap();
+        fs::remove_file(spool.pending.join(format!("{orphan}.eml")))
+            .await
+            .unwrap();
+
+        let due = spool.due().await.unwrap();
+        assert_eq!(due.len(), 2);
+        let mail = due.into_iter().find(|mail| mail.id == orphan).unwrap();
+        spool.dead_letter_orphan(mail).await.unwrap();
+        let due = spool.due().await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, other);
+        assert!(spool.dead.join(format!("{orphan}.json")).exists());
+        remove_spool(spool);
+    }
+
+    #[tokio::test]
+    async fn scheduled_mail_waits_until_cancelled() {
+        let spool = test_spool("scheduled").await;
+        let message = test_message();
+        let id = spool
+            .enqueue(message.envelope(), &message, Some(now() + 3600))
+            .await
+            .unwrap();
+        assert!(spool.due().await.unwrap().is_empty());
+        assert_eq!(spool.scheduled().await.unwrap()[0].id, id);
+
+        assert!(spool.cancel("../dead").await.unwrap().is_none());
+        assert!(spool.cancel(&id).await.unwrap().is_some());
+        assert!(spool.scheduled().await.unwrap().is_empty());
+        assert!(spool.cancel(&id).await.unwrap().is_none());
+        remove_spool(spool);
+    }
+
+    #[test]
+    fn retry_delay_is_exponential_and_capped() {
+        let spool = Spool {
+            pending: PathBuf::new(),
+            dead: PathBuf::new(),
+            attempts: 8,
+            backoff: 30,
+        };
+        assert_eq!(spool.retry_delay(1), 30);
+        assert_eq!(spool.retry_delay(2), 60);
+        assert_eq!(spool.retry_delay(3), 120);
+        assert_eq!(spool.retry_delay(40), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lettre::address::Envelope;
+use metrics::{counter, gauge};
+use tracing::debug;
+
+/// Outbound rate limits, in messages per second
+///
+/// | Setting name | Value                                              | Default value |
+/// | ------------ | -------------------------------------------------- | ------------- |
+/// | global       | messages per second across all recipients          | unlimited     |
+/// | domains      | map of recipient domain to messages per second     | empty         |
+///
+/// Sends over a limit are delayed until they fit, never rejected.
+/// Each limit allows a burst of up to one second's worth of messages.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct RateLimitConfig {
+    pub global: Option<f64>,
+    pub domains: HashMap<String, f64>,
+}
+
+/// A token bucket which hands out reservations
+///
+/// Tokens may go negative, in which case each caller is told how
+/// long to wait for its own token, so waiting sends go out in order.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning how long to wait before using it
+    fn reserve(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    global: Option<Mutex<Bucket>>,
+    domains: HashMap<String, Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig) -> Self {
+        let valid = |rate: &f64| rate.is_finite() && *rate > 0.0;
+        Self {
+            global: config
+                .global
+                .filter(valid)
+                .map(|rate| Mutex::new(Bucket::new(rate))),
+            domains: config
+                .domains
+                .iter()
+                .filter(|(_, rate)| valid(rate))
+                .map(|(domain, rate)| (domain.to_ascii_lowercase(), Mutex::new(Bucket::new(*rate))))
+                .collect(),
+        }
+    }
+
+    /// Waits until a message to these recipients can be sent without exceeding any limit
+    pub(crate) async fn wait(&self, envelope: &Envelope) {
+        let now = Instant::now();
+        let domains: BTreeSet<String> = envelope
+            .to()
+            .iter()
+            .map(|address| address.domain().to_ascii_lowercase())
+            .collect();
+
+        let mut delays: Vec<(&str, Duration)> = Vec::new();
+        if let Some(bucket) = &self.global {
+            delays.push(("global", bucket.lock().unwrap().reserve(now)));
+        }
+        for domain in &domains {
+            if let Some((domain, bucket)) = self.domains.get_key_value(domain) {
+                delays.push((domain.as_str(), bucket.lock().unwrap().reserve(now)));
+            }
+        }
+
+        let Some((limit, delay)) = delays.into_iter().max_by_key(|(_, delay)| *delay) else {
+            return;
+        };
+        if delay.is_zero() {
+            return;
+        }
+
+        debug!("Delaying send by {delay:?} for the {limit} rate limit");
+        let throttled = gauge!("mails_throttled", "limit" => limit.to_owned());
+        throttled.increment(1.0);
+        counter!("mails_throttled_total", "limit" => limit.to_owned()).increment(1);
+        tokio::time::sleep(delay).await;
+        throttled.decrement(1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_spaces_sends() {
+        let mut bucket = Bucket::new(2.0);
+        let start = bucket.last;
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.reserve(start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(start), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = Bucket::new(1.0);
+        let start = bucket.last;
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(
+            bucket.reserve(start + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lettre::{
+    address::Envelope,
+    transport::smtp::{response::Response, Error},
+    AsyncTransport,
+};
+use metrics::{counter, gauge};
+use tracing::{debug, warn};
+
+use crate::{
+    serve::{mailer, SmtpMailerConfig},
+    transport::SmtpTransport,
+};
+
+/// Circuit breaker settings shared by every relay
+///
+/// | Setting name | Value                                                     | Default value |
+/// | ------------ | --------------------------------------------------------- | ------------- |
+/// | threshold    | consecutive failures before a relay is taken out of use   | `3`           |
+/// | cooldown     | seconds before a failed relay is tried again              | `30`          |
+/// | probe        | seconds between health checks of each relay, `0` for off | `0`           |
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct FailoverConfig {
+    pub threshold: u32,
+    pub cooldown: u64,
+    pub probe: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            cooldown: 30,
+            // Checks connect to every relay, so they're only made when asked for
+            probe: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    /// While set, the circuit is open and the relay is skipped
+    open_until: Option<Instant>,
+}
+
+struct Relay {
+    name: String,
+    transport: SmtpTransport,
+    health: Mutex<Health>,
+}
+
+impl Relay {
+    fn available(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .open_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.open_until.is_some() {
+            debug!("SMTP relay {} has recovered", self.name);
+        }
+        *health = Health::default();
+        gauge!("smtp_relay_circuit_open", "relay" => self.name.clone()).set(0.0);
+    }
+
+    fn record_failure(&self, threshold: u32, cooldown: Duration) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        counter!("smtp_relay_failures_total", "relay" => self.name.clone()).increment(1);
+        if health.failures >= threshold {
+            if health.open_until.is_none() {
+                warn!("Taking SMTP relay {} out of use", self.name);
+            }
+            health.open_until = Some(Instant::now() + cooldown);
+            gauge!("smtp_relay_circuit_open", "relay" => self.name.clone()).set(1.0);
+        }
+    }
+}
+
+/// Whether an error means another relay might succeed where this one failed
+fn should_fail_over(error: &Error) -> bool {
+    !error.is_permanent()
+}
+
+/// An ordered list of SMTP relays
+///
+/// Mail goes to the first healthy relay. Connection errors and transient
+/// (4xx) rejections count against a relay, and move on to the next one.
+/// After enough consecutive failures a relay's circuit opens, and it is
+/// skipped until its cooldown has passed.
+pub(crate) struct Relays {
+    relays: Vec<Relay>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Relays {
+    /// Creates a pool from a list of relay configurations, which must not be empty
+    pub(crate) fn new(configs: Vec<SmtpMailerConfig>, failover: &FailoverConfig) -> Self {
+        assert!(!configs.is_empty(), "at least one SMTP relay is required");
+        let relays = configs
+            .into_iter()
+            .map(|config| {
+                let name = config.name();
+                gauge!("smtp_relay_circuit_open", "relay" => name.clone()).set(0.0);
+                Relay {
+                    name,
+                    transport: mailer(config),
+                    health: Default::default(),
+                }
+            })
+            .collect();
+        Self {
+            relays,
+            threshold: failover.threshold.max(1),
+            cooldown: Duration::from_secs(failover.cooldown),
+        }
+    }
+
+    pub(crate) async fn send_raw(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<Response, Error> {
+        // Relays with an open circuit are still tried as a last resort,
+        // rather than failing without even trying.
+        let (available, unavailable): (Vec<_>, Vec<_>) =
+            self.relays.iter().partition(|r| r.available());
+        let mut last_error = None;
+        for relay in available.into_iter().chain(unavailable) {
+            match relay.transport.send_raw(envelope, email).await {
+                Ok(res) => {
+                    relay.record_success();
+                    return Ok(res);
+                }
+                Err(e) if should_fail_over(&e) => {
+                    warn!("Failed to send through SMTP relay {}: {e}", relay.name);
+                    relay.record_failure(self.threshold, self.cooldown);
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    // The relay is working, it just doesn't want this message
+                    relay.record_success();
+                    return Err(e);
+                }
+            }
+        }
+        Err(last_error.expect("there is at least one relay"))
+    }
+
+    /// Periodically checks that every relay is reachable
+    pub(crate) async fn probe(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            for relay in &self.relays {
+                match relay.transport.test_connection().await {
+                    Ok(true) => relay.record_success(),
+                    Ok(false) => relay.record_failure(self.threshold, self.cooldown),
+                    Err(e) => {
+                        warn!("Health check of SMTP relay {} failed: {e}", relay.name);
+                        relay.record_failure(self.threshold, self.cooldown)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relays(threshold: u32, cooldown: u64) -> Relays {
+        Relays::new(
+            vec![Default::default()],
+            &FailoverConfig {
+                threshold,
+                cooldown,
+                probe: 0,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_threshold() {
+        let pool = relays(2, 60);
+        let relay = &pool.relays[0];
+        relay.record_failure(pool.threshold, pool.cooldown);
+        assert!(relay.available());
+        relay.record_failure(pool.threshold, pool.cooldown);
+        assert!(!relay.available());
+        relay.record_success();
+        assert!(relay.available());
+    }
+
+    #[tokio::test]
+    async fn circuit_closes_after_cooldown() {
+        let pool = relays(1, 0);
+        let relay = &pool.relays[0];
+        relay.record_failure(pool.threshold, pool.cooldown);
+        assert!(relay.available());
+    }
+}
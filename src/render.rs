@@ -1,18 +1,19 @@
 use axum::{
-    extract::{Path, Query},
-    http::{header, StatusCode},
+    extract::Path,
+    http::header,
     response::{IntoResponse, Response},
-    Json,
 };
-use mrml::prelude::parser::noop_loader::NoopIncludeLoader;
+use mrml::{mjml::Mjml, prelude::parser::noop_loader::NoopIncludeLoader};
 use serde::Deserialize;
 use serde_json::Value;
 use std::borrow::Cow;
 use utoipa::IntoParams;
 
 use crate::{
+    error::{ApiError, ErrorCode},
+    extract::{Json, Query},
     locale_from_optional_code,
-    templates::{self, TemplateError},
+    templates::{self, Params, TemplateError, TemplateMetadata},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -31,14 +32,33 @@ pub(crate) enum EngineError {
     FailedTextConversion(#[from] html2text::Error),
 }
 
-impl IntoResponse for EngineError {
-    fn into_response(self) -> axum::response::Response {
-        tracing::error!("{self}: {self:?}");
+impl EngineError {
+    pub(crate) fn code(&self) -> ErrorCode {
         match self {
-            EngineError::TemplateNotFound(_) => (StatusCode::NOT_FOUND, format!("{self}")),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("{self}")),
+            EngineError::BadLanguageCode(_) => ErrorCode::BadLanguage,
+            EngineError::Template(_) => ErrorCode::BadParams,
+            EngineError::Parse(_) => ErrorCode::BadMjml,
+            EngineError::TemplateNotFound(_) => ErrorCode::UnknownTemplate,
+            EngineError::Render(_) | EngineError::FailedTextConversion(_) => {
+                ErrorCode::RenderFailed
+            }
         }
-        .into_response()
+    }
+}
+
+impl From<&EngineError> for ApiError {
+    fn from(value: &EngineError) -> Self {
+        let fields = match value {
+            EngineError::Template(e) => e.fields(),
+            _ => Vec::new(),
+        };
+        ApiError::from_error(value.code(), value).with_fields(fields)
+    }
+}
+
+impl IntoResponse for EngineError {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(&self).into_response()
     }
 }
 
@@ -49,36 +69,54 @@ pub(crate) struct RenderQuery {
     lang: Option<String>,
 }
 
-pub async fn render_template(
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct RenderParamsQuery {
+    /// Language to render the template with
+    lang: Option<String>,
+    /// Fill in missing params and ignore unknown ones, rather than rejecting them
+    #[serde(default)]
+    lenient: bool,
+}
+
+/// Builds the MJML of a template, to be rendered with [`render_root`]
+pub fn template_mjml(
     template_id: String,
-    params: Value,
+    params: Params,
     lang: crate::Locale,
-) -> Result<(String, Option<String>), EngineError> {
+) -> Result<Mjml, EngineError> {
     let template =
         templates::get(&template_id).ok_or(EngineError::TemplateNotFound(template_id))?;
-    let root = template(params, lang)?;
-    let opts = mrml::prelude::render::RenderOptions::default();
-    let content = root.render(&opts)?;
-    Ok((content, root.get_title()))
+    Ok(template(params, lang)?)
 }
 
-pub async fn render_mjml(mjml_text: String) -> Result<(String, Option<String>), EngineError> {
-    let opts = mrml::prelude::render::RenderOptions::default();
-
+pub fn parse_mjml(mjml_text: String) -> Result<Mjml, EngineError> {
     let resolver = Box::<NoopIncludeLoader>::default();
     let parser_options = mrml::prelude::parser::ParserOptions {
         include_loader: resolver,
     };
-    let root = mrml::parse_with_options(mjml_text, &parser_options)?;
+    Ok(mrml::parse_with_options(mjml_text, &parser_options)?)
+}
+
+/// Renders MJML to HTML, along with its title
+pub fn render_root(root: &Mjml) -> Result<(String, Option<String>), EngineError> {
+    let opts = mrml::prelude::render::RenderOptions::default();
     let content = root.render(&opts)?;
     Ok((content, root.get_title()))
 }
 
+pub async fn render_template(
+    template_id: String,
+    params: Params,
+    lang: crate::Locale,
+) -> Result<(String, Option<String>), EngineError> {
+    render_root(&template_mjml(template_id, params, lang)?)
+}
+
 #[utoipa::path(
     get,
     path = "/templates/{template_id}/html",
     responses(
-        (status = 200, description = "Template rendered successfully"),
+        (status = 200, description = "Template rendered with its example params"),
         (status = NOT_FOUND, description = "Template was not found")
     ),
     params(
@@ -91,8 +129,9 @@ pub async fn render_html_route_get(
     Query(RenderQuery { lang }): Query<RenderQuery>,
 ) -> Result<Response, EngineError> {
     let lang = locale_from_optional_code(lang)?;
+    let params = templates::example(&template_id).unwrap_or_default();
 
-    let (content, _title) = render_template(template_id, Value::Null, lang).await?;
+    let (content, _title) = render_template(template_id, Params::strict(params), lang).await?;
 
     Ok(([(header::CONTENT_TYPE, "text/html")], content).into_response())
 }
@@ -102,25 +141,88 @@ pub async fn render_html_route_get(
     path = "/templates/{template_id}/html",
     responses(
         (status = 200, description = "Template rendered successfully"),
-        (status = NOT_FOUND, description = "Template was not found")
+        (status = NOT_FOUND, description = "Template was not found"),
+        (status = UNPROCESSABLE_ENTITY, description = "The params are missing fields the template needs, or have ones it doesn't know", body = ApiError)
     ),
     params(
         ("template_id" = String, Path, description = "Template to render"),
-        RenderQuery
+        RenderParamsQuery
     ),
-    request_body = Value
+    request_body(content = Value, description = "The params of the template, as given by `GET /templates/{template_id}/schema`")
 )]
 pub async fn render_html_route_post(
     Path(template_id): Path<String>,
-    Query(RenderQuery { lang }): Query<RenderQuery>,
+    Query(RenderParamsQuery { lang, lenient }): Query<RenderParamsQuery>,
     Json(body): Json<Value>,
 ) -> Result<Response, EngineError> {
     let lang = locale_from_optional_code(lang)?;
-    let (content, _title) = render_template(template_id, body, lang).await?;
+    let (content, _title) = render_template(template_id, Params::new(body, lenient), lang).await?;
 
     Ok(([(header::CONTENT_TYPE, "text/html")], content).into_response())
 }
 
+#[utoipa::path(
+    get,
+    path = "/templates",
+    responses(
+        (status = 200, description = "Every template", body = [TemplateMetadata]),
+    )
+)]
+pub async fn list_templates_route() -> Json<Vec<TemplateMetadata>> {
+    Json(
+        templates::all()
+            .iter()
+            .map(TemplateMetadata::from)
+            .collect(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates/{template_id}",
+    responses(
+        (status = 200, description = "The template", body = TemplateMetadata),
+        (status = NOT_FOUND, description = "Template was not found", body = ApiError)
+    ),
+    params(
+        ("template_id" = String, Path, description = "Template to describe"),
+    )
+)]
+pub async fn template_route(
+    Path(template_id): Path<String>,
+) -> Result<Json<TemplateMetadata>, EngineError> {
+    let template =
+        templates::info(&template_id).ok_or(EngineError::TemplateNotFound(template_id))?;
+    Ok(Json(template.into()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/templates/{template_id}/schema",
+    responses(
+        (status = 200, description = "JSON Schema of the params the template takes", body = Value),
+        (status = NOT_FOUND, description = "Template was not found", body = ApiError)
+    ),
+    params(
+        ("template_id" = String, Path, description = "Template to describe"),
+    )
+)]
+pub async fn params_schema_route(
+    Path(template_id): Path<String>,
+) -> Result<Json<Value>, EngineError> {
+    let (name, schema) =
+        templates::params_schema(&template_id).ok_or(EngineError::TemplateNotFound(template_id))?;
+    let mut schema = serde_json::to_value(schema).expect("schemas can be serialised");
+    if let Value::Object(schema) = &mut schema {
+        schema.insert(
+            "$schema".to_owned(),
+            "https://json-schema.org/draft/2020-12/schema".into(),
+        );
+        schema.insert("title".to_owned(), name.into());
+    }
+    Ok(Json(schema))
+}
+
 pub async fn render_text(html: &str) -> Result<String, EngineError> {
     let config = html2text::config::plain()
         .no_table_borders()
@@ -146,7 +248,7 @@ white-space: pre !important;
     get,
     path = "/templates/{template_id}/text",
     responses(
-        (status = 200, description = "Template rendered successfully"),
+        (status = 200, description = "Template rendered with its example params"),
         (status = NOT_FOUND, description = "Template was not found")
     ),
     params(
@@ -159,7 +261,8 @@ pub async fn render_text_route_get(
     Query(RenderQuery { lang }): Query<RenderQuery>,
 ) -> Result<Response, EngineError> {
     let lang = locale_from_optional_code(lang)?;
-    let (html, _title) = render_template(template_id, Value::Null, lang).await?;
+    let params = templates::example(&template_id).unwrap_or_default();
+    let (html, _title) = render_template(template_id, Params::strict(params), lang).await?;
     let content = render_text(&html).await?;
 
     Ok((
@@ -174,21 +277,22 @@ pub async fn render_text_route_get(
     path = "/templates/{template_id}/text",
     responses(
         (status = 200, description = "Template rendered successfully"),
-        (status = NOT_FOUND, description = "Template was not found")
+        (status = NOT_FOUND, description = "Template was not found"),
+        (status = UNPROCESSABLE_ENTITY, description = "The params are missing fields the template needs, or have ones it doesn't know", body = ApiError)
     ),
     params(
         ("template_id" = String, Path, description = "Template to render"),
-        RenderQuery
+        RenderParamsQuery
     ),
-    request_body = Value
+    request_body(content = Value, description = "The params of the template, as given by `GET /templates/{template_id}/schema`")
 )]
 pub async fn render_text_route_post(
     Path(template_id): Path<String>,
-    Query(RenderQuery { lang }): Query<RenderQuery>,
+    Query(RenderParamsQuery { lang, lenient }): Query<RenderParamsQuery>,
     Json(body): Json<Value>,
 ) -> Result<Response, EngineError> {
     let lang = locale_from_optional_code(lang)?;
-    let (html, _title) = render_template(template_id, body, lang).await?;
+    let (html, _title) = render_template(template_id, Params::new(body, lenient), lang).await?;
     let content = render_text(&html).await?;
 
     Ok((
@@ -203,13 +307,17 @@ mod test {
     use expect_test::expect_file;
     use serde_json::{json, Value};
 
-    use crate::Locale;
+    use crate::{templates::Params, Locale};
 
     #[tokio::test]
     async fn basic_template_html() {
-        let (res, _) = super::render_template("basic".to_string(), Value::Null, Locale::default())
-            .await
-            .unwrap();
+        let (res, _) = super::render_template(
+            "basic".to_string(),
+            Params::strict(Value::Null),
+            Locale::default(),
+        )
+        .await
+        .unwrap();
         let expected = expect_file!["../fixtures/basic.html"];
         expected.assert_eq(&res);
     }
@@ -218,7 +326,7 @@ mod test {
     async fn subscription_template_html() {
         let (res, _) = super::render_template(
             "subscription".to_string(),
-            json!({
+            Params::strict(json!({
                 "to_name": "Jade",
                 "subscription_settings_url": "https://example.com/prefs",
                 "edit_subscriptions_url": "https://example.com/subscribed",
@@ -239,7 +347,7 @@ mod test {
                     }
                   ]
                 }
-              }),
+              })),
             Locale::default(),
         )
         .await
@@ -250,9 +358,13 @@ mod test {
 
     #[tokio::test]
     async fn basic_template_text() {
-        let (html, _) = super::render_template("basic".to_string(), Value::Null, Locale::default())
-            .await
-            .unwrap();
+        let (html, _) = super::render_template(
+            "basic".to_string(),
+            Params::strict(Value::Null),
+            Locale::default(),
+        )
+        .await
+        .unwrap();
         let res: String = super::render_text(&html).await.unwrap();
         let expected = expect_file!["../fixtures/basic.txt"];
         expected.assert_eq(&res);
@@ -262,7 +374,7 @@ mod test {
     async fn subscription_template_text() {
         let (html, _) = super::render_template(
             "subscription".to_string(),
-            json!({
+            Params::strict(json!({
                 "to_name": "Jade",
                 "subscription_settings_url": "https://example.com/prefs",
                 "edit_subscriptions_url": "https://example.com/subscribed",
@@ -283,7 +395,7 @@ mod test {
                     }
                   ]
                 }
-              }),
+              })),
             Locale::default(),
         )
         .await
@@ -1,18 +1,42 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
 use futures::prelude::*;
 use lettre::{
-    message::{MessageBuilder, MultiPart, SinglePart},
-    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    address::{Address, Envelope},
+    message::{Mailbox, MessageBuilder, MultiPart, SinglePart},
+    transport::smtp::response::Response as SmtpResponse,
+    Message,
 };
 use metrics::counter;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::time::SystemTime;
+use time::OffsetDateTime;
 use tracing::trace;
-use utoipa::ToSchema;
+use utoipa::{
+    openapi::{
+        schema::{AllOfBuilder, Discriminator, ObjectBuilder, OneOfBuilder, Type},
+        OpenApi, Ref,
+    },
+    IntoParams, Modify, ToSchema,
+};
 
 use crate::{
+    attachments::{with_attachments, Attachment},
+    error::{ApiError, ErrorCode},
+    extract::{Json, Query},
+    idempotency::{IdempotencyKey, IdempotencyMismatch},
+    jobs::JobStatus,
     locale_from_optional_code,
-    render::{render_mjml, render_template, render_text, EngineError},
+    queue::{now, rfc3339, unique_id},
+    render::{parse_mjml, render_root, render_text, template_mjml, EngineError},
+    serve::AppState,
+    templates::{self, mail_unsubscribe_category, Category, Params},
+    transport::TransportError,
+    Locale,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -20,22 +44,74 @@ pub(crate) enum SendError {
     #[error("Failed to render template: {0}")]
     FailedTemplate(#[from] EngineError),
     #[error("Failed to send mail: {0}")]
-    SmtpError(#[from] lettre::transport::smtp::Error),
-    #[error("Bad email address: {0}")]
-    AddressError(#[from] lettre::address::AddressError),
+    TransportError(#[from] TransportError),
+    #[error("The relay rejected the mail with {code}: {message}")]
+    Rejected { code: u16, message: String },
+    #[error("Bad email address in {field} ({address:?}): {source}")]
+    AddressError {
+        field: String,
+        address: String,
+        source: lettre::address::AddressError,
+    },
+    #[error("No recipients were given")]
+    NoRecipients,
+    #[error("No from address was given, and the template has no default")]
+    NoSender,
+    #[error("Invalid Message-ID in {field}: {id:?}")]
+    InvalidMessageId { field: String, id: String },
+    #[error("Mail can't be sent from {field} address {address}, as its domain is not allowed")]
+    DomainNotAllowed {
+        field: &'static str,
+        address: String,
+    },
+    #[error("Scheduled delivery needs the outbound queue to be enabled")]
+    SchedulingDisabled,
+    #[error("Staging mode doesn't allow mail to {}", .0.join(", "))]
+    NotAllowed(Vec<String>),
+    #[error("Failed to queue mail: {0}")]
+    QueueError(#[from] std::io::Error),
+    #[error("Bad attachment {filename}: {reason}")]
+    AttachmentError { filename: String, reason: String },
+    #[error("{0}")]
+    IdempotencyConflict(#[from] IdempotencyMismatch),
 }
 
-impl IntoResponse for SendError {
-    fn into_response(self) -> axum::response::Response {
-        tracing::error!("{self}: {self:?}");
+impl SendError {
+    pub(crate) fn code(&self) -> ErrorCode {
         match self {
-            Self::FailedTemplate(err) => err.into_response(),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("{self}")).into_response(),
+            Self::FailedTemplate(e) => e.code(),
+            Self::TransportError(e) => e.code(),
+            Self::Rejected { code, .. } if (400..500).contains(code) => ErrorCode::SmtpTransient,
+            Self::Rejected { .. } => ErrorCode::SmtpPermanent,
+            Self::AddressError { .. } | Self::NoRecipients | Self::NoSender => {
+                ErrorCode::BadAddress
+            }
+            Self::InvalidMessageId { .. }
+            | Self::SchedulingDisabled
+            | Self::AttachmentError { .. } => ErrorCode::BadRequest,
+            Self::NotAllowed(_) | Self::DomainNotAllowed { .. } => ErrorCode::Forbidden,
+            Self::QueueError(_) => ErrorCode::Internal,
+            Self::IdempotencyConflict(_) => ErrorCode::IdempotencyConflict,
         }
     }
 }
 
-pub type MailTransport = AsyncSmtpTransport<Tokio1Executor>;
+impl From<SendError> for ApiError {
+    fn from(value: SendError) -> Self {
+        let fields = match &value {
+            SendError::FailedTemplate(EngineError::Template(e)) => e.fields(),
+            _ => Vec::new(),
+        };
+        ApiError::from_error(value.code(), &value).with_fields(fields)
+    }
+}
+
+impl IntoResponse for SendError {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::from(self).into_response()
+    }
+}
+
 trait OptionalSubject {
     fn subject_opt<S>(self, subject: Option<S>) -> Self
     where
@@ -52,26 +128,108 @@ impl OptionalSubject for MessageBuilder {
     }
 }
 
-/// All the data needed to send a single email based on a template
-#[derive(Deserialize, ToSchema, Clone)]
-pub struct SendTemplateItem {
-    /// Template to send
-    template_id: String,
-    /// The address the email is from
-    from: String,
+/// One address, or a list of them.
+/// Each address may have a display name, like `"Jade" <jade@example.org>`.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+#[serde(untagged)]
+pub enum Addresses {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for Addresses {
+    fn default() -> Self {
+        Self::Many(Vec::new())
+    }
+}
+
+impl Addresses {
+    /// Parses every address, naming the first bad one in the error
+    fn parse(self, field: &str) -> Result<Vec<Mailbox>, SendError> {
+        match self {
+            Addresses::One(address) => Ok(vec![parse_mailbox(field.to_owned(), address)?]),
+            Addresses::Many(addresses) => addresses
+                .into_iter()
+                .enumerate()
+                .map(|(i, address)| parse_mailbox(format!("{field}[{i}]"), address))
+                .collect(),
+        }
+    }
+}
+
+fn parse_mailbox(field: String, address: String) -> Result<Mailbox, SendError> {
+    address.parse().map_err(|source| SendError::AddressError {
+        field,
+        address,
+        source,
+    })
+}
+
+/// Whether a Message-ID follows the `msg-id` syntax of RFC 5322, like `<id@example.org>`
+fn is_message_id(id: &str) -> bool {
+    fn is_dot_atom(text: &str) -> bool {
+        text.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c))
+        })
+    }
+    let Some((left, right)) = id
+        .strip_prefix('<')
+        .and_then(|id| id.strip_suffix('>'))
+        .and_then(|id| id.split_once('@'))
+    else {
+        return false;
+    };
+    let is_literal = right
+        .strip_prefix('[')
+        .and_then(|literal| literal.strip_suffix(']'))
+        .is_some_and(|dtext| {
+            dtext
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"[]\\".contains(c))
+        });
+    is_dot_atom(left) && (is_dot_atom(right) || is_literal)
+}
+
+fn check_message_id(field: String, id: &str) -> Result<(), SendError> {
+    if is_message_id(id) {
+        Ok(())
+    } else {
+        Err(SendError::InvalidMessageId {
+            field,
+            id: id.to_owned(),
+        })
+    }
+}
+
+/// Addressing and threading fields shared by every kind of send request
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+pub struct MessageFields {
+    /// The address the email is from.
+    /// Defaults to the one configured for the template.
+    #[serde(default)]
+    from: Option<String>,
     /// The address ultimately sending the email
     /// Should not be set if same as from address, as per RFC
     sender: Option<String>,
-    /// Address to send mail to.
-    to: String,
+    /// Addresses to send mail to.
+    to: Addresses,
+    /// Addresses to copy the mail to
+    #[serde(default)]
+    cc: Addresses,
+    /// Addresses to blindly copy the mail to.
+    /// They receive the mail, but are not listed in its headers.
+    #[serde(default)]
+    bcc: Addresses,
     /// Reply-To email header
     reply_to: Option<String>,
-    /// Language to render the template with
-    lang: Option<String>,
-    /// Data to pass to the template
-    params: Value,
-    /// A unique identifier for the email
-    /// Please see https://www.ietf.org/rfc/rfc2822.html#section-3.6.4
+    /// The envelope sender, which bounces are sent to
+    return_path: Option<String>,
+    /// A unique identifier for the email, like `<id@example.org>`.
+    /// One is generated if not given.
+    /// Please see https://www.ietf.org/rfc/rfc5322.html#section-3.6.4
     message_id: Option<String>,
     /// The unique identifiers of the emails to which this is replying
     #[serde(default)]
@@ -79,179 +237,563 @@ pub struct SendTemplateItem {
     /// The unique identifiers of the emails that this email references
     #[serde(default)]
     references: Vec<String>,
+    /// Files to attach, or to embed in the HTML body
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    /// Send the email at this time rather than straight away, in RFC 3339 format.
+    /// Needs the outbound queue to be enabled.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    send_at: Option<OffsetDateTime>,
+}
+
+impl MessageFields {
+    /// Every recipient, whether in `to`, `cc` or `bcc`
+    fn recipients(&self) -> Result<Vec<Mailbox>, SendError> {
+        let mut recipients = self.to.clone().parse("to")?;
+        recipients.extend(self.cc.clone().parse("cc")?);
+        recipients.extend(self.bcc.clone().parse("bcc")?);
+        Ok(recipients)
+    }
+
+    /// Fields for a plain mail from one address to another
+    pub(crate) fn new(from: String, to: String) -> Self {
+        Self {
+            from: Some(from),
+            sender: None,
+            to: Addresses::One(to),
+            cc: Addresses::default(),
+            bcc: Addresses::default(),
+            reply_to: None,
+            return_path: None,
+            message_id: None,
+            in_reply_to: Vec::new(),
+            references: Vec::new(),
+            attachments: Vec::new(),
+            send_at: None,
+        }
+    }
+}
+
+/// All the data needed to send a single email based on a template
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
+pub struct SendTemplateItem {
+    /// Template to send
+    template_id: String,
+    /// Language to render the template with
+    lang: Option<String>,
+    /// Data to pass to the template. Which fields it takes depends on `template_id`.
+    params: Value,
+    /// Fill in missing params and ignore unknown ones, rather than rejecting them.
+    /// For older clients, which may not send every field a template needs.
+    #[serde(default)]
+    lenient: bool,
+    /// An opaque reference for the caller's own use.
+    /// It is echoed back in the results of bulk sends.
+    client_ref: Option<String>,
+    #[serde(flatten)]
+    fields: MessageFields,
+}
+
+impl SendTemplateItem {
+    pub(crate) fn new(
+        template_id: String,
+        lang: Option<String>,
+        params: Value,
+        fields: MessageFields,
+    ) -> Self {
+        Self {
+            template_id,
+            lang,
+            params,
+            lenient: false,
+            client_ref: None,
+            fields,
+        }
+    }
+}
+
+/// Documents the params of each template, as a `oneOf` keyed on `template_id`
+pub(crate) struct TemplateParams;
+
+impl Modify for TemplateParams {
+    fn modify(&self, openapi: &mut OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let mut variants = OneOfBuilder::new();
+        let mut mapping = Vec::new();
+        for (template_id, name, schema) in templates::params_schemas() {
+            let variant = format!("SendTemplateItem.{template_id}");
+            components.schemas.insert(name.clone(), schema);
+            components.schemas.insert(
+                variant.clone(),
+                ObjectBuilder::new()
+                    .property(
+                        "template_id",
+                        ObjectBuilder::new()
+                            .schema_type(Type::String)
+                            .enum_values(Some([template_id])),
+                    )
+                    .required("template_id")
+                    .property("params", Ref::from_schema_name(name))
+                    .required("params")
+                    .into(),
+            );
+            variants = variants.item(Ref::from_schema_name(&variant));
+            mapping.push((template_id, format!("#/components/schemas/{variant}")));
+        }
+        let variants =
+            variants.discriminator(Some(Discriminator::with_mapping("template_id", mapping)));
+        if let Some(item) = components.schemas.remove("SendTemplateItem") {
+            components.schemas.insert(
+                "SendTemplateItem".to_owned(),
+                AllOfBuilder::new().item(item).item(variants).into(),
+            );
+        }
+    }
 }
 
 /// All the data needed to send a single email based on a template
-#[derive(Deserialize, ToSchema, Clone)]
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug)]
 pub struct SendMjmlItem {
     /// The MJML body to render and send
     mjml_text: String,
-
-    /// The address the email is from
-    from: String,
-    /// The address ultimately sending the email
-    /// Should not be set if same as from address, as per RFC
-    sender: Option<String>,
-    /// Address to send mail to.
-    to: String,
-    /// Reply-To email header
-    reply_to: Option<String>,
-    /// A unique identifier for the email
-    /// Please see https://www.ietf.org/rfc/rfc2822.html#section-3.6.4
-    message_id: Option<String>,
-    /// The unique identifiers of the emails to which this is replying
-    #[serde(default)]
-    in_reply_to: Vec<String>,
-    /// The unique identifiers of the emails that this email references
-    #[serde(default)]
-    references: Vec<String>,
+    #[serde(flatten)]
+    fields: MessageFields,
 }
 
-#[derive(Serialize, ToSchema, Clone)]
+#[derive(Serialize, ToSchema, Clone, Debug)]
 #[serde(tag = "t", content = "c")]
 pub enum SendResponse {
-    Success { code: u16, message: String },
-    Error { message: String },
+    Success {
+        code: u16,
+        message: String,
+        /// The Message-ID of the email, for threading replies to it
+        message_id: Option<String>,
+        /// The envelope sender, which bounces are sent to
+        envelope_from: Option<String>,
+        /// Every address the email was sent to
+        recipients: Vec<String>,
+    },
+    /// The mail was written to the outbound queue and will be delivered in the background
+    Queued {
+        id: String,
+        /// The Message-ID of the email, for threading replies to it
+        message_id: Option<String>,
+        /// The envelope sender, which bounces are sent to
+        envelope_from: Option<String>,
+        /// Every address the email will be sent to
+        recipients: Vec<String>,
+    },
+    /// The mail was written to the outbound queue, to be sent at a later time
+    Scheduled {
+        id: String,
+        /// When the mail will be sent, in RFC 3339 format
+        send_at: String,
+        /// The Message-ID of the email, for threading replies to it
+        message_id: Option<String>,
+        /// The envelope sender, which bounces are sent to
+        envelope_from: Option<String>,
+        /// Every address the email will be sent to
+        recipients: Vec<String>,
+    },
+    /// The mail was not sent, as every recipient is on the suppression list
+    Suppressed {
+        recipients: Vec<String>,
+    },
+    /// The mail was not sent, as staging mode doesn't allow any of the recipients
+    Dropped {
+        recipients: Vec<String>,
+    },
+    Error(ApiError),
+}
+
+impl SendResponse {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Success { .. } | Self::Suppressed { .. } | Self::Dropped { .. } => StatusCode::OK,
+            Self::Queued { .. } | Self::Scheduled { .. } => StatusCode::ACCEPTED,
+            Self::Error(error) => error.code.status(),
+        }
+    }
+}
+
+/// What happened to a message handed to [`dispatch`]
+#[derive(Debug)]
+pub(crate) enum Dispatched {
+    Sent {
+        response: SmtpResponse,
+        message_id: Option<String>,
+        envelope: Envelope,
+    },
+    Queued {
+        id: String,
+        message_id: Option<String>,
+        envelope: Envelope,
+    },
+    /// Queued to be sent at the `send_at` Unix timestamp
+    Scheduled {
+        id: String,
+        send_at: u64,
+        message_id: Option<String>,
+        envelope: Envelope,
+    },
+    /// Not sent to these suppressed recipients, as there were no others
+    Suppressed(Vec<String>),
+    /// Not sent to these recipients, as staging mode allows none of them
+    Dropped(Vec<String>),
+}
+
+impl From<Dispatched> for SendResponse {
+    fn from(value: Dispatched) -> Self {
+        match value {
+            Dispatched::Sent {
+                response,
+                message_id,
+                envelope,
+            } => SendResponse::Success {
+                code: response.code().into(),
+                message: response.message().fold(String::new(), |s, n| s + n + "\n"),
+                message_id,
+                envelope_from: envelope.from().map(ToString::to_string),
+                recipients: envelope.to().iter().map(ToString::to_string).collect(),
+            },
+            Dispatched::Queued {
+                id,
+                message_id,
+                envelope,
+            } => SendResponse::Queued {
+                id,
+                message_id,
+                envelope_from: envelope.from().map(ToString::to_string),
+                recipients: envelope.to().iter().map(ToString::to_string).collect(),
+            },
+            Dispatched::Scheduled {
+                id,
+                send_at,
+                message_id,
+                envelope,
+            } => SendResponse::Scheduled {
+                id,
+                send_at: rfc3339(send_at),
+                message_id,
+                envelope_from: envelope.from().map(ToString::to_string),
+                recipients: envelope.to().iter().map(ToString::to_string).collect(),
+            },
+            Dispatched::Suppressed(recipients) => SendResponse::Suppressed { recipients },
+            Dispatched::Dropped(recipients) => SendResponse::Dropped { recipients },
+        }
+    }
+}
+
+/// Header clients can set to make retrying a send safe
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// The key identifying repeats of a request to a route, if there is one
+///
+/// An explicit `Idempotency-Key` header takes priority over the Message-ID.
+fn idempotency_key(
+    route: &'static str,
+    headers: &HeaderMap,
+    fields: &MessageFields,
+    body: &impl Serialize,
+) -> Option<IdempotencyKey> {
+    let key = headers
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| fields.message_id.clone())?;
+    Some(IdempotencyKey::new(route, key, body))
 }
 
 #[utoipa::path(
     post,
     path = "/send_single",
     responses(
-        (status = 200, description = "Email sent successfully"),
-        (status = NOT_FOUND, description = "Template was not found")
+        (status = 200, description = "Email sent successfully, or was already sent by an earlier request with the same key"),
+        (status = ACCEPTED, description = "Email queued for delivery"),
+        (status = "4XX", description = "The mail can't be sent as requested. `code` tells why.", body = ApiError),
+        (status = "5XX", description = "Sending failed. The request can be retried if `retryable` is set.", body = ApiError)
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Repeats of a request with the same key, or the same `message_id`, get the original response instead of sending again"),
     ),
     request_body = SendTemplateItem,
 )]
 pub async fn send_mail_route(
-    State(mailer): State<MailTransport>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
     Json(item): Json<SendTemplateItem>,
 ) -> Result<(StatusCode, Json<SendResponse>), SendError> {
     counter!("mails_requested_total").increment(1);
-    let res = send_mail_template(&mailer, item).await?;
+    let key = idempotency_key("/send_single", &headers, &item.fields, &item);
+    let res = state
+        .idempotency
+        .run(key, || {
+            send_mail_template(&state, item).map_ok(SendResponse::from)
+        })
+        .await?;
     trace!("{:?}", res);
 
-    Ok((
-        if res.is_positive() {
-            StatusCode::OK
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
-        },
-        Json(SendResponse::Success {
-            code: res.code().into(),
-            message: res.message().fold(String::new(), |s, n| s + n + "\n"),
-        }),
-    ))
+    Ok((res.status(), Json(res)))
 }
 
 #[utoipa::path(
     post,
     path = "/send_single_mjml",
     responses(
-        (status = 200, description = "Email sent successfully"),
-        (status = NOT_FOUND, description = "Template was not found")
+        (status = 200, description = "Email sent successfully, or was already sent by an earlier request with the same key"),
+        (status = ACCEPTED, description = "Email queued for delivery"),
+        (status = "4XX", description = "The mail can't be sent as requested. `code` tells why.", body = ApiError),
+        (status = "5XX", description = "Sending failed. The request can be retried if `retryable` is set.", body = ApiError)
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Repeats of a request with the same key, or the same `message_id`, get the original response instead of sending again"),
     ),
     request_body = SendMjmlItem,
 )]
 pub async fn send_mail_mjml_route(
-    State(mailer): State<MailTransport>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
     Json(item): Json<SendMjmlItem>,
 ) -> Result<(StatusCode, Json<SendResponse>), SendError> {
     counter!("mails_requested_total").increment(1);
-    let res = send_mail_mjml(&mailer, item).await?;
+    let key = idempotency_key("/send_single_mjml", &headers, &item.fields, &item);
+    let res = state
+        .idempotency
+        .run(key, || {
+            send_mail_mjml(&state, item).map_ok(SendResponse::from)
+        })
+        .await?;
     trace!("{:?}", res);
 
-    Ok((
-        if res.is_positive() {
-            StatusCode::OK
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
-        },
-        Json(SendResponse::Success {
-            code: res.code().into(),
-            message: res.message().fold(String::new(), |s, n| s + n + "\n"),
-        }),
-    ))
+    Ok((res.status(), Json(res)))
+}
+
+pub(crate) const PAR_SENDERS: usize = 16;
+
+impl From<SendError> for SendResponse {
+    fn from(value: SendError) -> Self {
+        SendResponse::Error(value.into())
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct BulkQuery {
+    /// Run the bulk send as a background job, and respond immediately with its ID
+    #[serde(default)]
+    background: bool,
+}
+
+/// The result of a single item of a bulk send
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct BulkItemResponse {
+    /// Position of the item in the request
+    index: usize,
+    /// The `client_ref` given with the item, if any
+    client_ref: Option<String>,
+    /// The Message-ID of the email, if it could be built
+    message_id: Option<String>,
+    #[serde(flatten)]
+    result: SendResponse,
+}
+
+impl BulkItemResponse {
+    pub(crate) fn is_error(&self) -> bool {
+        matches!(self.result, SendResponse::Error(_))
+    }
 }
 
-const PAR_SENDERS: usize = 16;
+/// Sends one item of a bulk request, keeping track of where it came from
+///
+/// Items with a `message_id` which was recently sent are not sent again.
+pub(crate) async fn send_bulk_item(
+    state: &AppState,
+    index: usize,
+    mut item: SendTemplateItem,
+) -> BulkItemResponse {
+    let client_ref = item.client_ref.take();
+    let mut message_id = item.fields.message_id.clone();
+    let send_at = item.fields.send_at;
+    let key = message_id
+        .clone()
+        .map(|id| IdempotencyKey::new("/send_bulk", id, &item));
+    let result = state
+        .idempotency
+        .run(key, || async {
+            if let Some(recipients) = state.staging.dropped(&item.fields.recipients()?) {
+                return Ok(SendResponse::Dropped { recipients });
+            }
+            let email = build_template_message(state, item).await?;
+            message_id = email.headers().get_raw("Message-ID").map(str::to_owned);
+            dispatch(state, email, send_at)
+                .await
+                .map(SendResponse::from)
+        })
+        .await;
+    BulkItemResponse {
+        index,
+        client_ref,
+        message_id,
+        result: result.unwrap_or_else(SendResponse::from),
+    }
+}
 
-#[tracing::instrument(skip(mailer, items))]
+#[tracing::instrument(skip(state, items))]
 #[utoipa::path(
     post,
     path = "/send_bulk",
     responses(
-        (status = 200, description = "Bulk job ran successfully", body = [SendResponse])
+        (status = 200, description = "Bulk job ran successfully. Results are in the same order as the request.", body = [BulkItemResponse]),
+        (status = ACCEPTED, description = "Bulk job started in the background", body = JobStatus)
     ),
+    params(BulkQuery),
     request_body = Vec<SendTemplateItem>,
 )]
 pub async fn send_mail_bulk_route(
-    State(mailer): State<MailTransport>,
+    State(state): State<AppState>,
+    Query(BulkQuery { background }): Query<BulkQuery>,
     Json(items): Json<Vec<SendTemplateItem>>,
-) -> Result<Json<Vec<SendResponse>>, SendError> {
+) -> Result<Response, SendError> {
     counter!("mails_requested_total").increment(items.len().try_into().unwrap());
-    let all_results = dashmap::DashMap::new();
-    stream::iter(items)
+    if background {
+        let job = state.jobs.start(state.clone(), items);
+        return Ok((StatusCode::ACCEPTED, Json(job.status())).into_response());
+    }
+
+    let all_results: Vec<BulkItemResponse> = stream::iter(items)
         .enumerate()
-        .for_each_concurrent(PAR_SENDERS, |(i, item)| {
-            // This is a hack to not move some values
-            // https://stackoverflow.com/questions/58459643/
-            let mailer = &mailer;
-            let all_results = &all_results;
-            async move {
-                let res = send_mail_template(mailer, item).await;
-                all_results.insert(i, res);
-            }
-        })
+        .map(|(i, item)| send_bulk_item(&state, i, item))
+        // Runs up to PAR_SENDERS at once, while keeping the results in order
+        .buffered(PAR_SENDERS)
+        .collect()
         .await;
 
-    Ok(Json(
-        all_results
-            .into_read_only()
-            .values()
-            .map(|i| match i {
-                Ok(res) => SendResponse::Success {
-                    code: res.code().into(),
-                    message: res.message().fold(String::new(), |s, n| s + n + "\n"),
-                },
-
-                Err(e) => SendResponse::Error {
-                    message: e.to_string(),
-                },
-            })
-            .collect(),
-    ))
-}
-
-#[tracing::instrument(skip(mailer))]
-pub async fn send_mail_template(
-    mailer: &MailTransport,
-    SendTemplateItem {
-        template_id,
+    Ok(Json(all_results).into_response())
+}
+
+/// Builds a complete message from the shared fields and a rendered body
+///
+/// Addresses which weren't given are taken from the defaults configured for the template.
+/// Mail in an unsubscribe category gets `List-Unsubscribe` headers if it has one recipient.
+/// Without a Return-Path, the envelope sender is a VERP address identifying the template
+/// and recipient.
+fn build_message(
+    state: &AppState,
+    template_id: Option<&str>,
+    unsubscribe: Option<Category>,
+    MessageFields {
         from,
         sender,
         to,
-        lang,
-        params,
+        cc,
+        bcc,
         reply_to,
+        return_path,
         message_id,
         in_reply_to,
         references,
-    }: SendTemplateItem,
-) -> Result<lettre::transport::smtp::response::Response, SendError> {
-    let lang = locale_from_optional_code(lang)?;
-    let (html, title) = render_template(template_id, params, lang).await?;
-    let text = render_text(&html).await?;
+        attachments,
+        send_at,
+    }: MessageFields,
+    title: Option<String>,
+    html: String,
+    text: String,
+) -> Result<Message, SendError> {
+    let defaults = state.brands.defaults(template_id);
+    let or_default = |field: &str, address: Option<String>, default: Option<Mailbox>| {
+        address
+            .map(|address| parse_mailbox(field.to_owned(), address))
+            .transpose()
+            .map(|mailbox| mailbox.or(default))
+    };
+    let from = or_default("from", from, defaults.from)?.ok_or(SendError::NoSender)?;
+    let sender = or_default("sender", sender, defaults.sender)?;
+    for (field, mailbox) in [("from", Some(&from)), ("sender", sender.as_ref())] {
+        if let Some(mailbox) = mailbox.filter(|m| !state.brands.allows(&m.email)) {
+            return Err(SendError::DomainNotAllowed {
+                field,
+                address: mailbox.email.to_string(),
+            });
+        }
+    }
+    let return_path = or_default("return_path", return_path, None)?
+        .map(|mailbox| mailbox.email)
+        .or(defaults.return_path);
+    let message_id = match message_id {
+        Some(id) => {
+            check_message_id("message_id".to_owned(), &id)?;
+            id
+        }
+        None => {
+            let domain = defaults
+                .message_id_domain
+                .as_deref()
+                .unwrap_or(from.email.domain());
+            format!("<{}@{domain}>", unique_id())
+        }
+    };
+    for (i, id) in in_reply_to.iter().enumerate() {
+        check_message_id(format!("in_reply_to[{i}]"), id)?;
+    }
+    for (i, id) in references.iter().enumerate() {
+        check_message_id(format!("references[{i}]"), id)?;
+    }
+
     let mut email = Message::builder()
-        .from(from.parse()?)
-        .to(to.parse()?)
-        .subject_opt(title.as_deref())
-        .message_id(message_id);
+        .from(from)
+        .subject_opt(title)
+        .message_id(Some(message_id));
+    if let Some(send_at) = send_at {
+        email = email.date(SystemTime::from(send_at).max(SystemTime::now()));
+    }
     if let Some(sender) = sender {
-        email = email.sender(sender.parse()?);
+        email = email.sender(sender);
+    }
+    if let Some(reply_to) = or_default("reply_to", reply_to, defaults.reply_to)? {
+        email = email.reply_to(reply_to);
     }
-    if let Some(reply_to) = reply_to {
-        email = email.reply_to(reply_to.parse()?);
+    let mut to = to.parse("to")?;
+    let mut cc = cc.parse("cc")?;
+    let mut bcc = bcc.parse("bcc")?;
+    if to.is_empty() && cc.is_empty() && bcc.is_empty() {
+        return Err(SendError::NoRecipients);
+    }
+    let removed = state.staging.apply(&mut to, &mut cc, &mut bcc);
+    if to.is_empty() && cc.is_empty() && bcc.is_empty() {
+        return Err(SendError::NotAllowed(
+            removed
+                .iter()
+                .map(|mailbox| mailbox.email.to_string())
+                .collect(),
+        ));
+    }
+    if let Some(header) = state.staging.original_to(&removed) {
+        email = email.raw_header(header);
+    }
+    let recipients: Vec<Address> = to
+        .iter()
+        .chain(&cc)
+        .chain(&bcc)
+        .map(|mailbox| mailbox.email.clone())
+        .collect();
+    for header in state.unsubscribe.headers(unsubscribe, &recipients) {
+        email = email.raw_header(header);
+    }
+    if let Some(return_path) =
+        return_path.or_else(|| state.bounces.return_path(template_id, &recipients))
+    {
+        email = email.envelope(
+            Envelope::new(Some(return_path), recipients).expect("there is at least one recipient"),
+        );
+    }
+    for mailbox in to {
+        email = email.to(mailbox);
+    }
+    for mailbox in cc {
+        email = email.cc(mailbox);
+    }
+    for mailbox in bcc {
+        email = email.bcc(mailbox);
     }
     for id in in_reply_to.into_iter() {
         email = email.in_reply_to(id)
@@ -260,78 +802,169 @@ pub async fn send_mail_template(
         email = email.references(id)
     }
 
-    let email = email
-        .multipart(
-            MultiPart::alternative() // This is composed of two parts.
-                .singlepart(
-                    SinglePart::builder()
-                        .header(lettre::message::header::ContentType::TEXT_PLAIN)
-                        .body(text), // Every message should have a plain text fallback.
-                )
-                .singlepart(
-                    SinglePart::builder()
-                        .header(lettre::message::header::ContentType::TEXT_HTML)
-                        .body(html),
-                ),
+    let body = MultiPart::alternative() // This is composed of two parts.
+        .singlepart(
+            SinglePart::builder()
+                .header(lettre::message::header::ContentType::TEXT_PLAIN)
+                .body(text), // Every message should have a plain text fallback.
         )
-        .expect("failed to build email");
-    let res = mailer.send(email).await?;
+        .singlepart(
+            SinglePart::builder()
+                .header(lettre::message::header::ContentType::TEXT_HTML)
+                .body(html),
+        );
 
-    counter!("mails_sent_total").increment(1);
-    Ok(res)
+    Ok(email
+        .multipart(with_attachments(body, attachments)?)
+        .expect("failed to build email"))
 }
 
-#[tracing::instrument(skip(mailer))]
-pub async fn send_mail_mjml(
-    mailer: &MailTransport,
-    SendMjmlItem {
-        mjml_text,
-        from,
-        sender,
-        to,
-        reply_to,
-        message_id,
-        in_reply_to,
-        references,
-    }: SendMjmlItem,
-) -> Result<lettre::transport::smtp::response::Response, SendError> {
-    let (html, title) = render_mjml(mjml_text).await?;
+pub async fn build_template_message(
+    state: &AppState,
+    SendTemplateItem {
+        template_id,
+        lang,
+        params,
+        lenient,
+        client_ref: _,
+        fields,
+    }: SendTemplateItem,
+) -> Result<Message, SendError> {
+    let lang = locale_from_optional_code(lang)?;
+    let unsubscribe = mail_unsubscribe_category(&template_id, &params);
+    let mut root = template_mjml(template_id.clone(), Params::new(params, lenient), lang)?;
+    state
+        .staging
+        .add_banner(&mut root, &fields.recipients()?, lang);
+    let (html, title) = render_root(&root)?;
     let text = render_text(&html).await?;
-    let mut email = Message::builder()
-        .from(from.parse()?)
-        .to(to.parse()?)
-        .subject_opt(title.as_deref())
-        .message_id(message_id);
-    if let Some(sender) = sender {
-        email = email.sender(sender.parse()?);
+    build_message(
+        state,
+        Some(&template_id),
+        unsubscribe,
+        fields,
+        title,
+        html,
+        text,
+    )
+}
+
+pub async fn build_mjml_message(
+    state: &AppState,
+    SendMjmlItem { mjml_text, fields }: SendMjmlItem,
+) -> Result<Message, SendError> {
+    let mut root = parse_mjml(mjml_text)?;
+    // MJML mail has no language to go by
+    state
+        .staging
+        .add_banner(&mut root, &fields.recipients()?, Locale::default());
+    let (html, title) = render_root(&root)?;
+    let text = render_text(&html).await?;
+    build_message(state, None, None, fields, title, html, text)
+}
+
+/// Hands a formatted message to the transport, once the rate limits allow it
+pub(crate) async fn deliver(
+    state: &AppState,
+    envelope: &Envelope,
+    raw: &[u8],
+) -> Result<SmtpResponse, TransportError> {
+    state.ratelimit.wait(envelope).await;
+    let res = state.mailer.send_raw(envelope, raw).await;
+    if let Err(e) = &res {
+        state.suppressions.record_rejection(envelope, e).await;
     }
-    if let Some(reply_to) = reply_to {
-        email = email.reply_to(reply_to.parse()?);
+    res
+}
+
+/// Signs and sends a built message, or hands it to the outbound queue if one is configured
+///
+/// Suppressed recipients are left out of the envelope. Mail with a `send_at`
+/// in the future is held in the queue until then.
+pub(crate) async fn dispatch(
+    state: &AppState,
+    mut email: Message,
+    send_at: Option<OffsetDateTime>,
+) -> Result<Dispatched, SendError> {
+    let send_at = send_at
+        .map(|send_at| send_at.unix_timestamp().max(0) as u64)
+        .filter(|send_at| *send_at > now());
+    if send_at.is_some() && state.spool.is_none() {
+        return Err(SendError::SchedulingDisabled);
     }
-    for id in in_reply_to.into_iter() {
-        email = email.in_reply_to(id)
+    let Some(envelope) = state.suppressions.allowed(email.envelope()) else {
+        let recipients = email.envelope().to().iter().map(|a| a.to_string());
+        return Ok(Dispatched::Suppressed(recipients.collect()));
+    };
+    state.dkim.sign(&mut email);
+    let message_id = email.headers().get_raw("Message-ID").map(str::to_owned);
+    if let Some(spool) = &state.spool {
+        let id = spool.enqueue(&envelope, &email, send_at).await?;
+        return Ok(match send_at {
+            Some(send_at) => Dispatched::Scheduled {
+                id,
+                send_at,
+                message_id,
+                envelope,
+            },
+            None => Dispatched::Queued {
+                id,
+                message_id,
+                envelope,
+            },
+        });
     }
-    for id in references.into_iter() {
-        email = email.references(id)
+    let response = deliver(state, &envelope, &email.formatted()).await?;
+    if !response.is_positive() {
+        return Err(SendError::Rejected {
+            code: response.code().into(),
+            message: response.message().collect::<Vec<_>>().join(" "),
+        });
     }
 
-    let email = email
-        .multipart(
-            MultiPart::alternative() // This is composed of two parts.
-                .singlepart(
-                    SinglePart::builder()
-                        .header(lettre::message::header::ContentType::TEXT_PLAIN)
-                        .body(text), // Every message should have a plain text fallback.
-                )
-                .singlepart(
-                    SinglePart::builder()
-                        .header(lettre::message::header::ContentType::TEXT_HTML)
-                        .body(html),
-                ),
-        )
-        .expect("failed to build email");
-    let res = mailer.send(email).await?;
-
     counter!("mails_sent_total").increment(1);
-    Ok(res)
+    Ok(Dispatched::Sent {
+        response,
+        message_id,
+        envelope,
+    })
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn send_mail_template(
+    state: &AppState,
+    item: SendTemplateItem,
+) -> Result<Dispatched, SendError> {
+    if let Some(recipients) = state.staging.dropped(&item.fields.recipients()?) {
+        return Ok(Dispatched::Dropped(recipients));
+    }
+    let send_at = item.fields.send_at;
+    let email = build_template_message(state, item).await?;
+    dispatch(state, email, send_at).await
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn send_mail_mjml(state: &AppState, item: SendMjmlItem) -> Result<Dispatched, SendError> {
+    if let Some(recipients) = state.staging.dropped(&item.fields.recipients()?) {
+        return Ok(Dispatched::Dropped(recipients));
+    }
+    let send_at = item.fields.send_at;
+    let email = build_mjml_message(state, item).await?;
+    dispatch(state, email, send_at).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_are_validated() {
+        assert!(is_message_id("<1234.5678@musicbrainz.org>"));
+        assert!(is_message_id("<edit-note+1@[127.0.0.1]>"));
+        assert!(!is_message_id("1234@musicbrainz.org"));
+        assert!(!is_message_id("<1234@musicbrainz.org> extra"));
+        assert!(!is_message_id("<12 34@musicbrainz.org>"));
+        assert!(!is_message_id("<1234..5678@musicbrainz.org>"));
+        assert!(!is_message_id("<@musicbrainz.org>"));
+    }
 }
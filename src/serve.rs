@@ -4,11 +4,13 @@ use tower::ServiceBuilder;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
+use tower_http::trace::TraceLayer;
 
 use std::{
     borrow::Cow,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{net::TcpListener, signal};
@@ -17,16 +19,41 @@ use tokio::{net::TcpListener, signal};
 use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
 
 use crate::{
+    admin::{require_admin, Admin},
+    bounce::{bounce_route, Bounces},
+    brand::Brands,
+    digest::{self, add_digest_route, send_digests_route, Digests},
+    dkim::Dkim,
+    error::{ApiError, ErrorCode},
+    gallery::gallery_route,
+    idempotency::Idempotency,
+    jobs::{cancel_job_route, get_job_route, Jobs},
+    preview::{preview_mail_mjml_route, preview_mail_route},
+    queue::{self, cancel_scheduled_route, list_scheduled_route, Spool},
+    ratelimit::RateLimiter,
     render::{
-        render_html_route_get, render_html_route_post, render_text_route_get,
-        render_text_route_post,
+        list_templates_route, params_schema_route, render_html_route_get, render_html_route_post,
+        render_text_route_get, render_text_route_post, template_route,
     },
-    send::{send_mail_bulk_route, send_mail_mjml_route, send_mail_route, MailTransport},
+    send::{send_mail_bulk_route, send_mail_mjml_route, send_mail_route, SendResponse},
+    staging::Staging,
+    suppression::{
+        add_suppression_route, list_suppressions_route, remove_suppression_route, Suppressions,
+    },
+    transport::{captured_mail_route, clear_captured_mail_route, SmtpTransport, Transport},
+    unsubscribe::{unsubscribe_route, Unsubscribe},
+    Settings,
+};
+use lettre::transport::smtp::{
+    authentication::{Credentials, Mechanism},
+    AsyncSmtpTransportBuilder,
 };
+
 use axum::{
-    http::StatusCode,
-    response::Redirect,
-    routing::{get, post},
+    extract::Request,
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post},
     Json,
 };
 
@@ -38,17 +65,59 @@ use metrics::counter;
 #[openapi(
     paths(
         available_locales,
+        crate::render::list_templates_route,
+        crate::render::template_route,
         crate::render::render_html_route_get,
         crate::render::render_html_route_post,crate::render::render_text_route_get,crate::render::render_text_route_post,
+        crate::render::params_schema_route,
         crate::send::send_mail_route,
         crate::send::send_mail_mjml_route,
         crate::send::send_mail_bulk_route,
+        crate::preview::preview_mail_route,
+        crate::preview::preview_mail_mjml_route,
+        crate::jobs::get_job_route,
+        crate::jobs::cancel_job_route,
+        crate::transport::captured_mail_route,
+        crate::transport::clear_captured_mail_route,
+        crate::unsubscribe::unsubscribe_route,
+        crate::suppression::list_suppressions_route,
+        crate::suppression::add_suppression_route,
+        crate::suppression::remove_suppression_route,
+        crate::bounce::bounce_route,
+        crate::queue::list_scheduled_route,
+        crate::queue::cancel_scheduled_route,
+        crate::digest::add_digest_route,
+        crate::digest::send_digests_route,
         healthcheck
     ),
-    components(schemas(crate::send::SendTemplateItem, crate::send::SendResponse)),
+    components(schemas(
+        crate::send::SendTemplateItem,
+        crate::send::SendMjmlItem,
+        crate::send::MessageFields,
+        crate::send::Addresses,
+        crate::attachments::Attachment,
+        crate::send::SendResponse,
+        crate::send::BulkItemResponse,
+        crate::jobs::JobStatus,
+        crate::transport::CapturedMail,
+        crate::suppression::Suppression,
+        crate::suppression::AddSuppression,
+        crate::bounce::Bounce,
+        crate::bounce::BounceReport,
+        crate::queue::ScheduledMail,
+        crate::digest::DigestItem,
+        crate::preview::Preview,
+        crate::preview::PreviewHeader,
+        crate::error::ErrorCode,
+        crate::error::ApiError,
+        crate::templates::TemplateMetadata,
+        crate::templates::Brand,
+        crate::templates::TemplateCategory
+    )),
     tags(
         (name = "mb-mail-service", description = "MusicBrains Mail Service API")
-    )
+    ),
+    modifiers(&crate::send::TemplateParams)
 )]
 struct ApiDoc;
 
@@ -116,7 +185,44 @@ impl Default for ListenerConfig {
     }
 }
 
-async fn service(mailer: MailTransport) -> axum::Router {
+/// Shared state available to all routes
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub mailer: Transport,
+    /// The outbound queue, if enabled
+    pub spool: Option<Arc<Spool>>,
+    /// Background bulk sends
+    pub jobs: Arc<Jobs>,
+    pub ratelimit: Arc<RateLimiter>,
+    /// Responses to recent sends, to answer repeated requests
+    pub idempotency: Arc<Idempotency<SendResponse>>,
+    /// Signs built messages before they are queued or sent
+    pub dkim: Arc<Dkim>,
+    /// Signs and redeems one-click unsubscribe links
+    pub unsubscribe: Arc<Unsubscribe>,
+    /// Guards routes which change who is mailed
+    pub admin: Arc<Admin>,
+    /// Addresses which must not be mailed
+    pub suppressions: Arc<Suppressions>,
+    /// Builds and reads VERP return paths
+    pub bounces: Arc<Bounces>,
+    /// Events waiting to be sent as digests
+    pub digests: Arc<Digests>,
+    /// Redirects or drops mail on staging servers
+    pub staging: Arc<Staging>,
+    /// Default addresses, and the domains mail may be sent from
+    pub brands: Arc<Brands>,
+}
+
+/// Answers requests which take longer than a minute with an error
+async fn timeout(request: Request, next: Next) -> Response {
+    match tokio::time::timeout(Duration::from_secs(60), next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => ApiError::new(ErrorCode::Timeout, "The request took too long").into_response(),
+    }
+}
+
+async fn service(state: AppState) -> axum::Router {
     #[cfg(not(test))]
     let sentry_layer = ServiceBuilder::new()
         .layer(NewSentryLayer::new_from_top())
@@ -129,8 +235,12 @@ async fn service(mailer: MailTransport) -> axum::Router {
         .route("/", get(|| async { Redirect::temporary("/swagger-ui") }))
         // OpenAPI docs
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
+        // Previews of every template, for translators and designers
+        .route("/gallery", get(gallery_route))
         // Our routes
         .route("/available_locales", get(available_locales))
+        .route("/templates", get(list_templates_route))
+        .route("/templates/{template_id}", get(template_route))
         .route(
             "/templates/{template_id}/html",
             post(render_html_route_post),
@@ -141,10 +251,37 @@ async fn service(mailer: MailTransport) -> axum::Router {
             "/templates/{template_id}/text",
             post(render_text_route_post),
         )
+        .route("/templates/{template_id}/schema", get(params_schema_route))
         .route("/send_single", post(send_mail_route))
         .route("/send_single_mjml", post(send_mail_mjml_route))
+        .route("/preview_single", post(preview_mail_route))
+        .route("/preview_single_mjml", post(preview_mail_mjml_route))
         .route("/send_bulk", post(send_mail_bulk_route))
-        .with_state(mailer);
+        .route(
+            "/jobs/{job_id}",
+            get(get_job_route).delete(cancel_job_route),
+        )
+        .route(
+            "/captured_mail",
+            get(captured_mail_route).delete(clear_captured_mail_route),
+        )
+        .route("/unsubscribe/{token}", post(unsubscribe_route))
+        .route("/scheduled", get(list_scheduled_route))
+        .route("/scheduled/{id}", delete(cancel_scheduled_route))
+        .route("/digests", post(add_digest_route))
+        .route("/digests/send", post(send_digests_route))
+        // Routes which change who is mailed, only for the admin token
+        .merge(
+            axum::Router::new()
+                .route(
+                    "/suppressions",
+                    get(list_suppressions_route).post(add_suppression_route),
+                )
+                .route("/suppressions/{address}", delete(remove_suppression_route))
+                .route("/bounces", post(bounce_route))
+                .route_layer(middleware::from_fn_with_state(state.clone(), require_admin)),
+        )
+        .with_state(state);
 
     #[cfg(not(test))]
     let app = app.layer(sentry_layer);
@@ -154,7 +291,7 @@ async fn service(mailer: MailTransport) -> axum::Router {
             TraceLayer::new_for_http(),
             // Give a universal timeout to prevent
             // DOS and for graceful shutdown
-            TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(60)),
+            middleware::from_fn(timeout),
         ))
         // Place the healthcheck last to bypass previously set layers
         .route("/healthcheck", get(healthcheck));
@@ -166,11 +303,11 @@ async fn service(mailer: MailTransport) -> axum::Router {
     app
 }
 
-pub(crate) async fn serve(config: ListenerConfig, mailer_config: SmtpMailerConfig) {
+pub(crate) async fn serve(settings: Settings) {
     // If possible, we want to use a socket passed to the app by, eg, SystemD or ListenFD.
     // Otherwise, we will use [address] to get a socket.
     let mut listenfd = listenfd::ListenFd::from_env();
-    let listener = match config {
+    let listener = match settings.listen {
         ListenerConfig::FileDescriptor { .. } => {
             let listener = listenfd.take_tcp_listener(0).unwrap().unwrap();
             tracing::info!("server listening on socket");
@@ -195,9 +332,62 @@ pub(crate) async fn serve(config: ListenerConfig, mailer_config: SmtpMailerConfi
         }
     };
 
-    let mailer = mailer(mailer_config);
+    let relays = if settings.relays.is_empty() {
+        vec![settings.smtp]
+    } else {
+        settings.relays
+    };
+    let mailer = Transport::new(settings.transport, relays, &settings.failover);
+    if let Transport::Smtp(relays) = &mailer {
+        if settings.failover.probe > 0 {
+            tokio::spawn(
+                relays
+                    .clone()
+                    .probe(Duration::from_secs(settings.failover.probe)),
+            );
+        }
+    }
+
+    let spool = Spool::open(&settings.queue)
+        .await
+        .expect("failed to open mail queue")
+        .map(Arc::new);
+
+    let state = AppState {
+        mailer,
+        spool,
+        jobs: Default::default(),
+        ratelimit: Arc::new(RateLimiter::new(&settings.ratelimit)),
+        idempotency: Arc::new(Idempotency::new(&settings.idempotency)),
+        dkim: Arc::new(Dkim::new(settings.dkim).expect("failed to load DKIM keys")),
+        unsubscribe: Arc::new(
+            Unsubscribe::new(settings.unsubscribe).expect("invalid unsubscribe settings"),
+        ),
+        admin: Arc::new(Admin::new(settings.admin).expect("failed to read admin token")),
+        suppressions: Arc::new(
+            Suppressions::open(&settings.suppression).expect("failed to read suppression list"),
+        ),
+        bounces: Arc::new(Bounces::new(settings.bounce).expect("invalid bounce settings")),
+        digests: Arc::new(Digests::open(&settings.digest).expect("failed to read pending digests")),
+        staging: Arc::new(
+            Staging::new(settings.staging).expect("invalid staging redirect address"),
+        ),
+        brands: Arc::new(Brands::new(settings.brand).expect("invalid brand configuration")),
+    };
+
+    if let Some(spool) = &state.spool {
+        tracing::info!("queueing outbound mail in {:?}", settings.queue.dir);
+        tokio::spawn(queue::run(
+            spool.clone(),
+            state.clone(),
+            Duration::from_secs(settings.queue.interval.max(1)),
+        ));
+    }
+    if settings.digest.interval > 0 {
+        tokio::spawn(digest::run(state.clone(), settings.digest.interval));
+    }
 
-    let app = service(mailer).await;
+    let app = service(state).await;
     axum::serve(listener, app.into_make_service())
         .with_graceful_shutdown(shutdown_signal())
         .await
@@ -214,12 +404,91 @@ fn default_port() -> u16 {
     25
 }
 
+/// A secret value, given either directly or as a path to a file containing it
+///
+/// Reading secrets from files allows them to be provided through,
+/// for example, Docker secrets rather than plain environment variables.
+/// Leading and trailing whitespace in the file is ignored.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Secret {
+    Inline(String),
+    File { file: PathBuf },
+}
+
+impl Secret {
+    pub(crate) fn read(&self) -> std::io::Result<String> {
+        match self {
+            Secret::Inline(secret) => Ok(secret.clone()),
+            Secret::File { file } => Ok(std::fs::read_to_string(file)?.trim().to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Inline(_) => f.write_str("Inline(<redacted>)"),
+            Secret::File { file } => f.debug_struct("File").field("file", file).finish(),
+        }
+    }
+}
+
+/// Credentials for relays which require authentication
+///
+/// | Setting name  | Value                                  | Default value |
+/// | ------------- | -------------------------------------- | ------------- |
+/// | username      | string                                 | unset         |
+/// | password      | string                                 | unset         |
+/// | password.file | path to a file containing the password | unset         |
+/// | token         | XOAUTH2 access token                   | unset         |
+/// | token.file    | path to a file containing the token    | unset         |
+///
+/// If a token is set it is used with XOAUTH2, otherwise the password
+/// is used with PLAIN or LOGIN.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct SmtpAuth {
+    username: Option<String>,
+    password: Option<Secret>,
+    token: Option<Secret>,
+}
+
+impl SmtpAuth {
+    fn apply(
+        &self,
+        builder: AsyncSmtpTransportBuilder,
+    ) -> std::io::Result<AsyncSmtpTransportBuilder> {
+        let Some(username) = &self.username else {
+            if self.password.is_some() || self.token.is_some() {
+                tracing::warn!(
+                    "SMTP credentials were given without a username, so will not be used"
+                );
+            }
+            return Ok(builder);
+        };
+        Ok(match (&self.token, &self.password) {
+            (Some(token), _) => builder
+                .credentials(Credentials::new(username.clone(), token.read()?))
+                .authentication(vec![Mechanism::Xoauth2]),
+            (None, Some(password)) => builder
+                .credentials(Credentials::new(username.clone(), password.read()?))
+                .authentication(vec![Mechanism::Plain, Mechanism::Login]),
+            (None, None) => {
+                tracing::warn!("SMTP username was given without a password or token");
+                builder
+            }
+        })
+    }
+}
+
 /// | Setting name | Value                             | Default value |
 /// | ------------ | --------------------------------- | ------------- |
 /// | mode         | `Plaintext` \| `Startls` \| `Tls` | `Plaintext`   |
 /// | port         | unsigned integer                  | `25`          |
 /// | host         | hostname                          | `localhost`   |
 /// | timeout      | duration                          | 5 seconds     |
+///
+/// Any mode can also be given credentials, see [`SmtpAuth`].
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(untagged)]
@@ -234,6 +503,8 @@ pub(crate) enum SmtpMailerConfig {
         host: Cow<'static, str>,
         #[serde(default = "default_timeout")]
         timeout: Option<Duration>,
+        #[serde(flatten)]
+        auth: SmtpAuth,
     },
     Tls {
         #[allow(dead_code)]
@@ -244,6 +515,8 @@ pub(crate) enum SmtpMailerConfig {
         host: Cow<'static, str>,
         #[serde(default = "default_timeout")]
         timeout: Option<Duration>,
+        #[serde(flatten)]
+        auth: SmtpAuth,
     },
     Plaintext {
         #[serde(default = "default_port")]
@@ -252,50 +525,76 @@ pub(crate) enum SmtpMailerConfig {
         host: Cow<'static, str>,
         #[serde(default = "default_timeout")]
         timeout: Option<Duration>,
+        #[serde(flatten)]
+        auth: SmtpAuth,
     },
 }
 
+impl SmtpMailerConfig {
+    /// A name for the relay, used in logs and metrics
+    pub(crate) fn name(&self) -> String {
+        match self {
+            SmtpMailerConfig::Startls { port, host, .. }
+            | SmtpMailerConfig::Tls { port, host, .. }
+            | SmtpMailerConfig::Plaintext { port, host, .. } => format!("{host}:{port}"),
+        }
+    }
+}
+
 impl Default for SmtpMailerConfig {
     fn default() -> Self {
         Self::Plaintext {
             port: 25,
             host: Cow::Borrowed("localhost"),
             timeout: default_timeout(),
+            auth: SmtpAuth::default(),
         }
     }
 }
 
-pub(crate) fn mailer(config: SmtpMailerConfig) -> MailTransport {
-    match config {
+pub(crate) fn mailer(config: SmtpMailerConfig) -> SmtpTransport {
+    let (builder, auth) = match config {
         SmtpMailerConfig::Plaintext {
             port,
             host,
             timeout,
-        } => MailTransport::builder_dangerous(host)
-            .port(port)
-            .timeout(timeout)
-            .build(),
+            auth,
+        } => (
+            SmtpTransport::builder_dangerous(host)
+                .port(port)
+                .timeout(timeout),
+            auth,
+        ),
         SmtpMailerConfig::Startls {
             port,
             host,
             timeout,
             mode: _,
-        } => MailTransport::starttls_relay(&host)
-            .unwrap()
-            .port(port)
-            .timeout(timeout)
-            .build(),
+            auth,
+        } => (
+            SmtpTransport::starttls_relay(&host)
+                .unwrap()
+                .port(port)
+                .timeout(timeout),
+            auth,
+        ),
         SmtpMailerConfig::Tls {
             port,
             host,
             timeout,
             mode: _,
-        } => MailTransport::relay(&host)
-            .unwrap()
-            .port(port)
-            .timeout(timeout)
-            .build(),
-    }
+            auth,
+        } => (
+            SmtpTransport::relay(&host)
+                .unwrap()
+                .port(port)
+                .timeout(timeout),
+            auth,
+        ),
+    };
+    auth.apply(builder)
+        .expect("failed to read SMTP credentials")
+        .build()
 }
 
 /// This future resolves when either
@@ -333,16 +632,82 @@ mod tests {
     use axum_test::TestServer;
     use std::error::Error;
 
+    const ADMIN_TOKEN: &str = "hunter2";
+
+    fn test_bounces() -> Bounces {
+        Bounces::new(crate::bounce::BounceConfig {
+            domain: Some("bounces.musicbrainz.org".to_owned()),
+            secret: Some(Secret::Inline("hunter3".to_owned())),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
     async fn test_server() -> Result<TestServer, Box<dyn Error>> {
-        let mailer = mailer(Default::default());
+        test_server_with_spool(None).await
+    }
+
+    async fn test_server_with_spool(
+        spool: Option<Arc<Spool>>,
+    ) -> Result<TestServer, Box<dyn Error>> {
+        build_test_server(spool, Staging::default()).await
+    }
+
+    async fn test_server_with_staging(
+        staging: crate::staging::StagingConfig,
+    ) -> Result<TestServer, Box<dyn Error>> {
+        build_test_server(None, Staging::new(staging)?).await
+    }
+
+    async fn build_test_server(
+        spool: Option<Arc<Spool>>,
+        staging: Staging,
+    ) -> Result<TestServer, Box<dyn Error>> {
+        let mailer = Transport::new(
+            crate::transport::TransportConfig::Memory {
+                mode: Default::default(),
+            },
+            vec![Default::default()],
+            &Default::default(),
+        );
 
-        let server = axum_test::TestServer::builder()
-            .mock_transport()
-            .build(service(mailer).await)?;
+        let server = axum_test::TestServer::builder().mock_transport().build(
+            service(AppState {
+                mailer,
+                spool,
+                jobs: Default::default(),
+                ratelimit: Default::default(),
+                idempotency: Default::default(),
+                dkim: Default::default(),
+                unsubscribe: Default::default(),
+                admin: Arc::new(
+                    Admin::new(crate::admin::AdminConfig {
+                        token: Some(Secret::Inline(ADMIN_TOKEN.to_owned())),
+                    })
+                    .unwrap(),
+                ),
+                suppressions: Default::default(),
+                bounces: Arc::new(test_bounces()),
+                digests: Default::default(),
+                staging: Arc::new(staging),
+                brands: Default::default(),
+            })
+            .await,
+        )?;
 
         Ok(server)
     }
 
+    #[test]
+    fn secret_is_read_from_file() {
+        let path = std::env::temp_dir().join(format!("mb-mail-secret-{}", std::process::id()));
+        std::fs::write(&path, "hunter2\n").unwrap();
+        let secret = Secret::File { file: path.clone() };
+        assert_eq!(secret.read().unwrap(), "hunter2");
+        assert!(!format!("{:?}", Secret::Inline("hunter2".into())).contains("hunter2"));
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[tokio::test]
     async fn health_check_is_ok() -> Result<(), Box<dyn Error>> {
         let server = test_server().await?;
@@ -362,4 +727,721 @@ mod tests {
         assert!(locales.iter().any(|l| l == "en"));
         Ok(())
     }
+
+    #[tokio::test]
+    async fn background_bulk_job_reports_results() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let started = server
+            .post("/send_bulk")
+            .add_query_param("background", true)
+            .json(&serde_json::json!([{
+                "template_id": "does-not-exist",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "params": {},
+                "client_ref": "user-1"
+            }]))
+            .await;
+        started.assert_status(StatusCode::ACCEPTED);
+        let id = started.json::<serde_json::Value>()["id"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+
+        let status = loop {
+            let status = server.get(&format!("/jobs/{id}")).await;
+            status.assert_status(StatusCode::OK);
+            let status: serde_json::Value = status.json();
+            if status["state"] != "running" {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(status["state"], "completed");
+        assert_eq!(status["failed"], 1);
+        assert_eq!(status["results"][0]["t"], "Error");
+        assert_eq!(status["results"][0]["index"], 0);
+        assert_eq!(status["results"][0]["client_ref"], "user-1");
+
+        server
+            .delete(&format!("/jobs/{id}"))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        let status: serde_json::Value = server.get(&format!("/jobs/{id}")).await.json();
+        assert_eq!(status["state"], "completed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bulk_results_keep_request_order() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let items: Vec<_> = (0..40)
+            .map(|i| {
+                serde_json::json!({
+                    "template_id": "does-not-exist",
+                    "from": "noreply@musicbrainz.org",
+                    "to": "jade@example.org",
+                    "params": {},
+                    "client_ref": format!("ref-{i}")
+                })
+            })
+            .collect();
+        let res = server.post("/send_bulk").json(&items).await;
+        res.assert_status(StatusCode::OK);
+        let results: Vec<serde_json::Value> = res.json();
+        assert_eq!(results.len(), 40);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result["index"], i);
+            assert_eq!(result["client_ref"], format!("ref-{i}"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sent_mail_is_captured() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "params": {}
+            }))
+            .await;
+        res.assert_status(StatusCode::OK);
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0]["to"][0], "jade@example.org");
+        assert!(captured[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Hello world in English!"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_send_is_not_sent_again() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let item = serde_json::json!({
+            "template_id": "basic",
+            "from": "noreply@musicbrainz.org",
+            "to": "jade@example.org",
+            "params": {}
+        });
+        let first = server
+            .post("/send_single")
+            .add_header("Idempotency-Key", "password-reset-1")
+            .json(&item)
+            .await;
+        first.assert_status(StatusCode::OK);
+        let second = server
+            .post("/send_single")
+            .add_header("Idempotency-Key", "password-reset-1")
+            .json(&item)
+            .await;
+        second.assert_status(StatusCode::OK);
+        assert_eq!(
+            first.json::<serde_json::Value>(),
+            second.json::<serde_json::Value>()
+        );
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_key_must_match_the_request() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let item = |to: &str| {
+            serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": to,
+                "params": {}
+            })
+        };
+        server
+            .post("/send_single")
+            .add_header("Idempotency-Key", "welcome-1")
+            .json(&item("jade@example.org"))
+            .await
+            .assert_status(StatusCode::OK);
+
+        let res = server
+            .post("/send_single")
+            .add_header("Idempotency-Key", "welcome-1")
+            .json(&item("rob@example.org"))
+            .await;
+        res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            res.json::<serde_json::Value>()["code"],
+            "idempotency_conflict"
+        );
+
+        server
+            .post("/send_single_mjml")
+            .add_header("Idempotency-Key", "welcome-1")
+            .json(&serde_json::json!({
+                "mjml_text": "<mjml><mj-body></mj-body></mjml>",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org"
+            }))
+            .await
+            .assert_status(StatusCode::OK);
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attachments_are_added_to_mail() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single_mjml")
+            .json(&serde_json::json!({
+                "mjml_text": "<mjml><mj-body><mj-section><mj-column><mj-image src=\"cid:logo\" /></mj-column></mj-section></mj-body></mjml>",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "attachments": [
+                    {
+                        "filename": "logo.png",
+                        "content_type": "image/png",
+                        "content": "iVBORw0KGgo=",
+                        "content_id": "logo"
+                    },
+                    {
+                        "filename": "notes.txt",
+                        "content_type": "text/plain",
+                        "content": "SGVsbG8gd29ybGQ="
+                    }
+                ]
+            }))
+            .await;
+        res.assert_status(StatusCode::OK);
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        let message = captured[0]["message"].as_str().unwrap();
+        assert!(message.contains("multipart/mixed"));
+        assert!(message.contains("multipart/related"));
+        assert!(message.contains("Content-ID: <logo>"));
+        assert!(message.contains("filename=\"notes.txt\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mail_is_sent_to_every_recipient() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": ["\"Jade\" <jade@example.org>", "rob@example.org"],
+                "cc": "admins@musicbrainz.org",
+                "bcc": ["audit@musicbrainz.org"],
+                "params": {}
+            }))
+            .await;
+        res.assert_status(StatusCode::OK);
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured[0]["to"].as_array().unwrap().len(), 4);
+        let message = captured[0]["message"].as_str().unwrap();
+        let to = message.lines().find(|l| l.starts_with("To: ")).unwrap();
+        assert!(to.contains("Jade <jade@example.org>"));
+        assert!(to.contains("rob@example.org"));
+        assert!(message.contains("Cc: admins@musicbrainz.org"));
+        assert!(!message.contains("audit@musicbrainz.org"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_api_errors() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single")
+            .content_type("application/json")
+            .bytes("{\"template_id\": ".into())
+            .await;
+        res.assert_status(StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = res.json();
+        assert_eq!(body["code"], "bad_request");
+        assert_eq!(body["retryable"], false);
+        assert!(body["message"].is_string());
+
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({ "template_id": 1 }))
+            .await;
+        res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(res.json::<serde_json::Value>()["code"], "bad_params");
+
+        let res = server
+            .post("/send_bulk")
+            .add_query_param("background", "sometimes")
+            .json(&serde_json::json!([]))
+            .await;
+        res.assert_status(StatusCode::BAD_REQUEST);
+        assert_eq!(res.json::<serde_json::Value>()["code"], "bad_request");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_id_is_returned() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "params": {}
+            }))
+            .await;
+        res.assert_status(StatusCode::OK);
+        let body: serde_json::Value = res.json();
+        let message_id = body["c"]["message_id"].as_str().unwrap();
+        assert!(message_id.starts_with('<') && message_id.ends_with("@musicbrainz.org>"));
+        assert_eq!(body["c"]["recipients"][0], "jade@example.org");
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert!(captured[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("Message-ID: {message_id}")));
+
+        server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "message_id": "not a message id",
+                "params": {}
+            }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queued_mail_is_described() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("mb-mail-queue-{}", crate::queue::unique_id()));
+        let spool = Spool::open(&queue::QueueConfig {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        })
+        .await?;
+        let server = test_server_with_spool(spool.map(Arc::new)).await?;
+        for (send_at, kind) in [
+            (None, "Queued"),
+            (Some("2999-01-01T00:00:00Z"), "Scheduled"),
+        ] {
+            let res = server
+                .post("/send_single")
+                .json(&serde_json::json!({
+                    "template_id": "basic",
+                    "from": "noreply@musicbrainz.org",
+                    "to": "jade@example.org",
+                    "send_at": send_at,
+                    "params": {}
+                }))
+                .await;
+            res.assert_status(StatusCode::ACCEPTED);
+            let body: serde_json::Value = res.json();
+            assert_eq!(body["t"], kind);
+            assert!(body["c"]["message_id"]
+                .as_str()
+                .unwrap()
+                .ends_with("@musicbrainz.org>"));
+            assert_eq!(body["c"]["recipients"][0], "jade@example.org");
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preview_is_not_sent() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let item = serde_json::json!({
+            "template_id": "basic",
+            "from": "noreply@musicbrainz.org",
+            "to": "jade@example.org",
+            "message_id": "<preview@musicbrainz.org>",
+            "params": {}
+        });
+
+        let res = server.post("/preview_single").json(&item).await;
+        res.assert_status(StatusCode::OK);
+        res.assert_header("content-type", "message/rfc822");
+        assert!(res.text().contains("Message-ID: <preview@musicbrainz.org>"));
+
+        let res = server
+            .post("/preview_single")
+            .add_query_param("format", "json")
+            .json(&item)
+            .await;
+        res.assert_status(StatusCode::OK);
+        let preview: serde_json::Value = res.json();
+        assert_eq!(preview["envelope_to"][0], "jade@example.org");
+        assert!(preview["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|header| header["name"] == "Content-Type"
+                && header["value"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("multipart/alternative")));
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert!(captured.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bad_recipient_is_named() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": ["jade@example.org", "not an address"],
+                "params": {}
+            }))
+            .await;
+        res.assert_status(StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = res.json();
+        assert_eq!(error["code"], "bad_address");
+        assert_eq!(error["retryable"], false);
+        let message = error["message"].as_str().unwrap();
+        assert!(message.contains("to[1]"));
+        assert!(message.contains("not an address"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bulk_errors_are_classified() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .post("/send_bulk")
+            .json(&serde_json::json!([{
+                "template_id": "missing",
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "params": {}
+            }]))
+            .await;
+        let results: Vec<serde_json::Value> = res.json();
+        assert_eq!(results[0]["t"], "Error");
+        assert_eq!(results[0]["c"]["code"], "unknown_template");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn staging_drops_mail_without_allowed_recipients() -> Result<(), Box<dyn Error>> {
+        let server = test_server_with_staging(crate::staging::StagingConfig {
+            redirect: None,
+            allow: vec!["metabrainz.org".to_owned()],
+        })
+        .await?;
+        let mail = |to: &str| {
+            serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": to,
+                "params": {}
+            })
+        };
+
+        let res = server
+            .post("/send_single")
+            .json(&mail("jade@example.org"))
+            .await;
+        res.assert_status(StatusCode::OK);
+        let res: serde_json::Value = res.json();
+        assert_eq!(res["t"], "Dropped");
+        assert_eq!(
+            res["c"]["recipients"],
+            serde_json::json!(["jade@example.org"])
+        );
+
+        let res = server
+            .post("/send_bulk")
+            .json(&serde_json::json!([
+                mail("jade@example.org"),
+                mail("rob@metabrainz.org")
+            ]))
+            .await;
+        res.assert_status(StatusCode::OK);
+        let results: Vec<serde_json::Value> = res.json();
+        assert_eq!(results[0]["t"], "Dropped");
+        assert_eq!(results[1]["t"], "Success");
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn suppressed_address_is_not_mailed() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        server
+            .post("/suppressions")
+            .json(&serde_json::json!({ "address": "jade@example.org" }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .post("/suppressions")
+            .authorization_bearer(ADMIN_TOKEN)
+            .json(&serde_json::json!({ "address": "jade@example.org" }))
+            .await
+            .assert_status(StatusCode::OK);
+
+        let res = server
+            .post("/send_single")
+            .json(&serde_json::json!({
+                "template_id": "basic",
+                "from": "noreply@musicbrainz.org",
+                "to": "Jade@example.org",
+                "params": {}
+            }))
+            .await;
+        res.assert_status(StatusCode::OK);
+        assert_eq!(res.json::<serde_json::Value>()["t"], "Suppressed");
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert!(captured.is_empty());
+
+        server
+            .delete("/suppressions/jade@example.org")
+            .authorization_bearer(ADMIN_TOKEN)
+            .await
+            .assert_status(StatusCode::OK);
+        let suppressions: Vec<serde_json::Value> = server
+            .get("/suppressions")
+            .authorization_bearer(ADMIN_TOKEN)
+            .await
+            .json();
+        assert!(suppressions.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hard_bounce_suppresses_recipient() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let dsn = include_str!("../fixtures/dsn/hard_bounce.eml");
+        server
+            .post("/bounces")
+            .text(dsn)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // The fixture's bounce address isn't signed, so can't be trusted
+        let res = server
+            .post("/bounces")
+            .authorization_bearer(ADMIN_TOKEN)
+            .text(dsn)
+            .await;
+        res.assert_status(StatusCode::OK);
+        let report: serde_json::Value = res.json();
+        assert_eq!(report["bounces"][0]["suppressed"], false);
+
+        let recipient = "jade@example.org".parse()?;
+        let address = test_bounces()
+            .return_path(Some("edit-note"), &[recipient])
+            .unwrap();
+        let res = server
+            .post("/bounces")
+            .authorization_bearer(ADMIN_TOKEN)
+            .add_query_param("recipient", address.to_string())
+            .text(dsn)
+            .await;
+        res.assert_status(StatusCode::OK);
+        let report: serde_json::Value = res.json();
+        assert_eq!(report["template_id"], "edit-note");
+        assert_eq!(report["bounces"][0]["suppressed"], true);
+
+        let suppressions: Vec<serde_json::Value> = server
+            .get("/suppressions")
+            .authorization_bearer(ADMIN_TOKEN)
+            .await
+            .json();
+        assert_eq!(suppressions[0]["address"], "jade@example.org");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn digest_collects_events() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        for from_name in ["rob", "kim"] {
+            server
+                .post("/digests")
+                .json(&serde_json::json!({
+                    "from": "noreply@musicbrainz.org",
+                    "to": "jade@example.org",
+                    "digest_key": "edit-notes",
+                    "template_id": "edit-note",
+                    "params": {
+                        "edit_id": 1,
+                        "from_name": from_name,
+                        "message": "Hi"
+                    }
+                }))
+                .await
+                .assert_status(StatusCode::ACCEPTED);
+        }
+        server
+            .post("/digests")
+            .json(&serde_json::json!({
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "digest_key": "edit-notes",
+                "template_id": "reset-password"
+            }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        // `to_name` belongs to the digest, not to its events
+        server
+            .post("/digests")
+            .json(&serde_json::json!({
+                "from": "noreply@musicbrainz.org",
+                "to": "jade@example.org",
+                "digest_key": "edit-notes",
+                "template_id": "edit-note",
+                "params": { "to_name": "Jade", "edit_id": 1, "from_name": "rob", "message": "Hi" }
+            }))
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+        let sent: usize = server.post("/digests/send").await.json();
+        assert_eq!(sent, 1);
+
+        let captured: Vec<serde_json::Value> = server.get("/captured_mail").await.json();
+        assert_eq!(captured.len(), 1);
+        let message = captured[0]["message"].as_str().unwrap();
+        assert!(message.contains("Subject: Your latest updates"));
+        assert!(message.contains("rob:") && message.contains("kim:"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn template_params_are_checked() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let params = serde_json::json!({
+            "to_nmae": "Jade",
+            "reset_url": "https://musicbrainz.org/reset-password"
+        });
+        let res = server
+            .post("/templates/reset-password/text")
+            .json(&params)
+            .await;
+        res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let error: serde_json::Value = res.json();
+        assert_eq!(error["code"], "bad_params");
+        assert_eq!(error["fields"], serde_json::json!(["to_nmae", "to_name"]));
+
+        server
+            .post("/templates/reset-password/text")
+            .add_query_param("lenient", true)
+            .json(&params)
+            .await
+            .assert_status(StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn template_params_are_described() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server.get("/templates/reset-password/schema").await;
+        res.assert_status(StatusCode::OK);
+        let schema: serde_json::Value = res.json();
+        assert_eq!(schema["title"], "ResetPasswordParams");
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(
+            schema["required"],
+            serde_json::json!(["to_name", "reset_url"])
+        );
+
+        let schema: serde_json::Value = server.get("/templates/subscription/schema").await.json();
+        let sub_item = &schema["properties"]["edits"]["properties"]["artist"]["items"];
+        assert_eq!(sub_item["additionalProperties"], false);
+
+        server
+            .get("/templates/missing/schema")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let doc = serde_json::to_value(ApiDoc::openapi())?;
+        let variants = &doc["components"]["schemas"]["SendTemplateItem"]["allOf"][1];
+        assert_eq!(
+            variants["discriminator"]["mapping"]["edit-note"],
+            "#/components/schemas/SendTemplateItem.edit-note"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn templates_are_listed() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let templates: Vec<serde_json::Value> = server.get("/templates").await.json();
+        let thanks = templates.iter().find(|t| t["id"] == "thanks").unwrap();
+        assert_eq!(thanks["brand"], "listenbrainz");
+        assert_eq!(thanks["category"], "notification");
+
+        let res = server.get("/templates/reset-password").await;
+        res.assert_status(StatusCode::OK);
+        let template: serde_json::Value = res.json();
+        assert_eq!(template["brand"], "musicbrainz");
+        assert_eq!(template["category"], "transactional");
+        assert_eq!(template["example"]["to_name"], "Jade");
+
+        server
+            .get("/templates/missing")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let text = server.get("/templates/edit-note/text").await.text();
+        assert!(text.contains("Jade") && text.contains("123456"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gallery_shows_templates() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        let res = server
+            .get("/gallery")
+            .add_query_param("template", "edit-note")
+            .add_query_param("lang", "en")
+            .add_query_param("width", "mobile")
+            .await;
+        res.assert_status(StatusCode::OK);
+        let page = res.text();
+        assert!(page.contains("Subject: Note added to your edit #123456"));
+        assert!(page.contains("/gallery?template=reset-password&amp;lang=en&amp;width=mobile"));
+        assert!(page.contains("width: 375px"));
+        assert!(page.contains("<option value=\"de\">"));
+
+        server.get("/gallery").await.assert_status(StatusCode::OK);
+        server
+            .get("/gallery")
+            .add_query_param("template", "missing")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_job_is_not_found() -> Result<(), Box<dyn Error>> {
+        let server = test_server().await?;
+        server
+            .get("/jobs/unknown")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        Ok(())
+    }
 }
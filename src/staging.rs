@@ -0,0 +1,248 @@
+use lettre::{
+    address::AddressError,
+    message::{
+        header::{HeaderName, HeaderValue},
+        Mailbox,
+    },
+};
+use metrics::counter;
+use mrml::mjml::Mjml;
+use tracing::info;
+
+use crate::{components::staging_banner, Locale};
+
+/// Safety mode for beta and test servers, which must never mail real users
+///
+/// | Setting name | Value                                                     | Default value |
+/// | ------------ | --------------------------------------------------------- | ------------- |
+/// | redirect     | catch-all address mail for everyone else is sent to       | unset         |
+/// | allow        | list of addresses and domains which can be mailed as usual | empty         |
+///
+/// With only `allow` set, mail for anyone else is dropped. With neither
+/// set, mail goes to everyone.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct StagingConfig {
+    pub redirect: Option<String>,
+    pub allow: Vec<String>,
+}
+
+/// Keeps mail from staging servers away from real users
+#[derive(Debug, Default)]
+pub(crate) struct Staging {
+    redirect: Option<Mailbox>,
+    /// Lowercased addresses and domains
+    allow: Vec<String>,
+}
+
+impl Staging {
+    pub(crate) fn new(config: StagingConfig) -> Result<Self, AddressError> {
+        Ok(Self {
+            redirect: config.redirect.map(|r| r.parse()).transpose()?,
+            allow: config
+                .allow
+                .iter()
+                .map(|allowed| allowed.trim().to_lowercase())
+                .collect(),
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        self.redirect.is_some() || !self.allow.is_empty()
+    }
+
+    fn allows(&self, mailbox: &Mailbox) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        let address = mailbox.email.as_ref().to_lowercase();
+        let domain = mailbox.email.domain().to_lowercase();
+        self.allow
+            .iter()
+            .any(|allowed| *allowed == address || *allowed == domain)
+    }
+
+    /// Adds a banner naming the original recipients to mail which will be redirected
+    pub(crate) fn add_banner(&self, root: &mut Mjml, recipients: &[Mailbox], l: Locale) {
+        if self.redirect.is_none() {
+            return;
+        }
+        let redirected: Vec<String> = recipients
+            .iter()
+            .filter(|mailbox| !self.allows(mailbox))
+            .map(|mailbox| mailbox.email.to_string())
+            .collect();
+        if redirected.is_empty() {
+            return;
+        }
+        if let Some(body) = root.children.body.as_mut() {
+            body.children
+                .insert(0, staging_banner(&redirected.join(", "), l).into());
+        }
+    }
+
+    /// Takes recipients who aren't allowed out of the lists, and returns them
+    ///
+    /// When redirecting, the catch-all address is added to `to` in their place.
+    pub(crate) fn apply(
+        &self,
+        to: &mut Vec<Mailbox>,
+        cc: &mut Vec<Mailbox>,
+        bcc: &mut Vec<Mailbox>,
+    ) -> Vec<Mailbox> {
+        let mut removed = Vec::new();
+        for list in [&mut *to, cc, bcc] {
+            let (allowed, blocked): (Vec<_>, Vec<_>) = std::mem::take(list)
+                .into_iter()
+                .partition(|mailbox| self.allows(mailbox));
+            *list = allowed;
+            removed.extend(blocked);
+        }
+        if removed.is_empty() {
+            return removed;
+        }
+        match &self.redirect {
+            Some(redirect) => {
+                info!(
+                    "Redirecting mail for {} recipients to {redirect}",
+                    removed.len()
+                );
+                counter!("mails_redirected_total").increment(removed.len() as u64);
+                if !to.iter().any(|mailbox| mailbox.email == redirect.email) {
+                    to.push(redirect.clone());
+                }
+            }
+            None => {
+                info!(
+                    "Dropping {} recipients which are not allowed",
+                    removed.len()
+                );
+                counter!("mails_dropped_total").increment(removed.len() as u64);
+            }
+        }
+        removed
+    }
+
+    /// Every recipient, if none of them can be mailed and the mail is dropped
+    pub(crate) fn dropped(&self, recipients: &[Mailbox]) -> Option<Vec<String>> {
+        if self.redirect.is_some()
+            || recipients.is_empty()
+            || recipients.iter().any(|mailbox| self.allows(mailbox))
+        {
+            return None;
+        }
+        info!(
+            "Dropping mail for {} recipients which are not allowed",
+            recipients.len()
+        );
+        counter!("mails_dropped_total").increment(recipients.len() as u64);
+        Some(
+            recipients
+                .iter()
+                .map(|mailbox| mailbox.email.to_string())
+                .collect(),
+        )
+    }
+
+    /// The `X-Original-To` header naming the recipients of redirected mail
+    pub(crate) fn original_to(&self, removed: &[Mailbox]) -> Option<HeaderValue> {
+        if self.redirect.is_none() || removed.is_empty() {
+            return None;
+        }
+        let addresses: Vec<String> = removed
+            .iter()
+            .map(|mailbox| mailbox.email.to_string())
+            .collect();
+        Some(HeaderValue::new(
+            HeaderName::new_from_ascii_str("X-Original-To"),
+            addresses.join(", "),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailboxes(addresses: &[&str]) -> Vec<Mailbox> {
+        addresses.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn recipients_are_redirected() {
+        let staging = Staging::new(StagingConfig {
+            redirect: Some("catchall@test.musicbrainz.org".to_owned()),
+            allow: vec!["Tester@example.org".to_owned()],
+        })
+        .unwrap();
+        let mut to = mailboxes(&["jade@example.org", "tester@example.org"]);
+        let mut cc = mailboxes(&["rob@example.com"]);
+        let mut bcc = Vec::new();
+
+        let removed = staging.apply(&mut to, &mut cc, &mut bcc);
+        assert_eq!(removed, mailboxes(&["jade@example.org", "rob@example.com"]));
+        assert_eq!(
+            to,
+            mailboxes(&["tester@example.org", "catchall@test.musicbrainz.org"])
+        );
+        assert!(cc.is_empty());
+        let mut headers = lettre::message::header::Headers::new();
+        headers.insert_raw(staging.original_to(&removed).unwrap());
+        assert_eq!(
+            headers.get_raw("X-Original-To"),
+            Some("jade@example.org, rob@example.com")
+        );
+    }
+
+    #[test]
+    fn recipients_are_dropped_without_redirect() {
+        let staging = Staging::new(StagingConfig {
+            redirect: None,
+            allow: vec!["metabrainz.org".to_owned()],
+        })
+        .unwrap();
+        let mut to = mailboxes(&["jade@example.org", "rob@metabrainz.org"]);
+        let removed = staging.apply(&mut to, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(removed, mailboxes(&["jade@example.org"]));
+        assert_eq!(to, mailboxes(&["rob@metabrainz.org"]));
+        assert!(staging.original_to(&removed).is_none());
+    }
+
+    #[test]
+    fn mail_is_dropped_without_allowed_recipients() {
+        let staging = Staging::new(StagingConfig {
+            redirect: None,
+            allow: vec!["metabrainz.org".to_owned()],
+        })
+        .unwrap();
+        assert_eq!(
+            staging.dropped(&mailboxes(&["jade@example.org"])),
+            Some(vec!["jade@example.org".to_owned()])
+        );
+        assert_eq!(
+            staging.dropped(&mailboxes(&["jade@example.org", "rob@metabrainz.org"])),
+            None
+        );
+        assert_eq!(
+            Staging::default().dropped(&mailboxes(&["jade@example.org"])),
+            None
+        );
+    }
+
+    #[test]
+    fn banner_names_redirected_recipients() {
+        let staging = Staging::new(StagingConfig {
+            redirect: Some("catchall@test.musicbrainz.org".to_owned()),
+            allow: vec!["metabrainz.org".to_owned()],
+        })
+        .unwrap();
+        let mut root =
+            crate::render::parse_mjml("<mjml><mj-body></mj-body></mjml>".to_owned()).unwrap();
+        let recipients = mailboxes(&["jade@example.org", "rob@metabrainz.org"]);
+        staging.add_banner(&mut root, &recipients, Locale::default());
+
+        let (html, _) = crate::render::render_root(&root).unwrap();
+        assert!(html.contains("It was addressed to: jade@example.org"));
+        assert!(!html.contains("rob@metabrainz.org"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path as FsPath, PathBuf},
+    sync::Mutex,
+};
+
+use axum::extract::{Path, State};
+use lettre::address::Envelope;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    extract::Json,
+    queue::{now, write_atomic},
+    serve::AppState,
+    transport::TransportError,
+};
+
+/// Addresses which mail must not be sent to
+///
+/// | Setting name | Value                                        | Default value         |
+/// | ------------ | -------------------------------------------- | --------------------- |
+/// | file         | path to the JSON file storing the list       | unset (memory only)   |
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct SuppressionConfig {
+    pub file: Option<PathBuf>,
+}
+
+/// A suppressed address
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Suppression {
+    address: String,
+    /// Why the address was suppressed, such as the SMTP rejection
+    reason: String,
+    /// Unix timestamp (seconds) of when the address was suppressed
+    added: u64,
+}
+
+/// A request to suppress an address
+#[derive(Deserialize, ToSchema, Debug)]
+pub struct AddSuppression {
+    address: String,
+    /// Why the address is being suppressed
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// SMTP replies meaning the mailbox itself is unusable, rather than the
+/// message being refused: 550 mailbox unavailable, 551 user not local
+/// and 553 mailbox name not allowed.
+///
+/// Other permanent failures say nothing lasting about the address, so
+/// suppressing on them would stop mail to mailboxes which work: 552 is
+/// a full mailbox, which RFC 5321 lets clients treat as temporary, and
+/// 554 is mostly sent for the message or its sender, such as when it's
+/// taken for spam or the sending IP is blocklisted.
+const MAILBOX_REJECTIONS: [u16; 3] = [550, 551, 553];
+
+/// The persistent list of addresses not to send to
+///
+/// Addresses are compared case-insensitively. The whole list is rewritten
+/// on every change, which is fine for the thousands of entries expected.
+#[derive(Debug, Default)]
+pub(crate) struct Suppressions {
+    file: Option<PathBuf>,
+    entries: Mutex<BTreeMap<String, Suppression>>,
+    /// Serialises writes of the file, so they land in order
+    write: tokio::sync::Mutex<()>,
+}
+
+fn key(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+impl Suppressions {
+    pub(crate) fn open(config: &SuppressionConfig) -> std::io::Result<Self> {
+        let entries = match &config.file {
+            Some(file) if file.exists() => {
+                let list: Vec<Suppression> = serde_json::from_slice(&std::fs::read(file)?)?;
+                list.into_iter()
+                    .map(|entry| (key(&entry.address), entry))
+                    .collect()
+            }
+            _ => BTreeMap::new(),
+        };
+        Ok(Self {
+            file: config.file.clone(),
+            entries: Mutex::new(entries),
+            write: Default::default(),
+        })
+    }
+
+    pub(crate) fn list(&self) -> Vec<Suppression> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    pub(crate) fn is_suppressed(&self, address: &str) -> bool {
+        self.entries.lock().unwrap().contains_key(&key(address))
+    }
+
+    /// The envelope without any suppressed recipients, or `None` if all of them are
+    pub(crate) fn allowed(&self, envelope: &Envelope) -> Option<Envelope> {
+        let to: Vec<_> = envelope
+            .to()
+            .iter()
+            .filter(|address| !self.is_suppressed(address.as_ref()))
+            .cloned()
+            .collect();
+        if to.len() == envelope.to().len() {
+            return Some(envelope.clone());
+        }
+        counter!("mails_suppressed_total").increment((envelope.to().len() - to.len()) as u64);
+        Envelope::new(envelope.from().cloned(), to).ok()
+    }
+
+    async fn save(&self, file: &FsPath) -> std::io::Result<()> {
+        let _write = self.write.lock().await;
+        let list = self.list();
+        write_atomic(file, &serde_json::to_vec_pretty(&list)?).await
+    }
+
+    pub(crate) async fn add(&self, address: &str, reason: String) -> std::io::Result<Suppression> {
+        let entry = Suppression {
+            address: address.trim().to_owned(),
+            reason,
+            added: now(),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key(address), entry.clone());
+        if let Some(file) = &self.file {
+            self.save(file).await?;
+        }
+        Ok(entry)
+    }
+
+    pub(crate) async fn remove(&self, address: &str) -> std::io::Result<Option<Suppression>> {
+        let removed = self.entries.lock().unwrap().remove(&key(address));
+        if let (Some(_), Some(file)) = (&removed, &self.file) {
+            self.save(file).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Suppresses the recipient of a message which was refused because of its address
+    ///
+    /// Only single recipient messages are handled, as with several
+    /// recipients there's no telling which of them was refused.
+    pub(crate) async fn record_rejection(&self, envelope: &Envelope, error: &TransportError) {
+        let Some(code) = error.smtp_code() else {
+            return;
+        };
+        let [recipient] = envelope.to() else {
+            return;
+        };
+        self.record_reply(recipient.as_ref(), code, error.to_string())
+            .await;
+    }
+
+    /// Suppresses an address the relay replied to with `code`, if that means its mailbox is unusable
+    async fn record_reply(&self, address: &str, code: u16, reason: String) {
+        if !MAILBOX_REJECTIONS.contains(&code) {
+            return;
+        }
+        info!("Suppressing {address} after it was rejected with {code}");
+        if let Err(e) = self.add(address, reason).await {
+            warn!("Failed to save suppression of {address}: {e}");
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/suppressions",
+    responses(
+        (status = 200, description = "Every suppressed address", body = [Suppression]),
+    )
+)]
+pub async fn list_suppressions_route(State(state): State<AppState>) -> Json<Vec<Suppression>> {
+    Json(state.suppressions.list())
+}
+
+#[utoipa::path(
+    post,
+    path = "/suppressions",
+    responses(
+        (status = 200, description = "The address was suppressed", body = Suppression),
+        (status = BAD_REQUEST, description = "The address is not valid"),
+    ),
+    request_body = AddSuppression,
+)]
+pub async fn add_suppression_route(
+    State(state): State<AppState>,
+    Json(AddSuppression { address, reason }): Json<AddSuppression>,
+) -> Result<Json<Suppression>, ApiError> {
+    address
+        .trim()
+        .parse::<lettre::Address>()
+        .map_err(|e| ApiError::new(ErrorCode::BadAddress, format!("{address:?}: {e}")))?;
+    let reason = reason.unwrap_or_else(|| "Added manually".to_owned());
+    Ok(Json(
+        state
+            .suppressions
+            .add(&address, reason)
+            .await
+            .map_err(ApiError::internal)?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/suppressions/{address}",
+    responses(
+        (status = 200, description = "The address was removed from the list", body = Suppression),
+        (status = NOT_FOUND, description = "The address is not suppressed"),
+    ),
+    params(
+        ("address" = String, Path, description = "Address to stop suppressing"),
+    )
+)]
+pub async fn remove_suppression_route(
+    State(state): State<AppState>,
+    Path(address): Path<String>,
+) -> Result<Json<Suppression>, ApiError> {
+    state
+        .suppressions
+        .remove(&address)
+        .await
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Address is not suppressed: {address}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::unique_id;
+
+    #[tokio::test]
+    async fn suppressions_are_persisted() {
+        let file = std::env::temp_dir().join(format!("mb-mail-suppressions-{}", unique_id()));
+        let config = SuppressionConfig {
+            file: Some(file.clone()),
+        };
+        let suppressions = Suppressions::open(&config).unwrap();
+        suppressions
+            .add("Jade@Example.org", "550 no such user".into())
+            .await
+            .unwrap();
+        suppressions
+            .add("rob@example.org", "550 no such user".into())
+            .await
+            .unwrap();
+        suppressions.remove("rob@example.org").await.unwrap();
+
+        let reopened = Suppressions::open(&config).unwrap();
+        assert!(reopened.is_suppressed("jade@example.org"));
+        assert!(!reopened.is_suppressed("rob@example.org"));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn suppressed_recipients_are_removed_from_envelope() {
+        let suppressions = Suppressions::default();
+        suppressions
+            .add("jade@example.org", "550 no such user".into())
+            .await
+            .unwrap();
+        let from = Some("noreply@musicbrainz.org".parse().unwrap());
+
+        let both = Envelope::new(
+            from.clone(),
+            vec![
+                "jade@example.org".parse().unwrap(),
+                "rob@example.org".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        let allowed = suppressions.allowed(&both).unwrap();
+        assert_eq!(allowed.to().len(), 1);
+        assert_eq!(allowed.to()[0].as_ref(), "rob@example.org");
+
+        let only = Envelope::new(from, vec!["jade@example.org".parse().unwrap()]).unwrap();
+        assert!(suppressions.allowed(&only).is_none());
+    }
+
+    #[tokio::test]
+    async fn only_mailbox_rejections_suppress() {
+        let suppressions = Suppressions::default();
+        suppressions
+            .record_reply("jade@example.org", 554, "554 rejected as spam".into())
+            .await;
+        suppressions
+            .record_reply("jade@example.org", 552, "552 mailbox full".into())
+            .await;
+        assert!(!suppressions.is_suppressed("jade@example.org"));
+
+        suppressions
+            .record_reply("jade@example.org", 550, "550 no such user".into())
+            .await;
+        assert!(suppressions.is_suppressed("jade@example.org"));
+    }
+}
@@ -1,10 +1,21 @@
-use mrml::mjml::Mjml;
-use serde_json::Value;
+use std::fmt::Display;
+
+use mrml::{mj_body::MjBodyChild, mjml::Mjml};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use utoipa::{
+    openapi::{
+        schema::{AdditionalProperties, ArrayItems, Schema},
+        RefOr,
+    },
+    ToSchema,
+};
 
 use crate::Locale;
 
 mod basic;
 mod cb_review;
+mod digest;
 mod edit_note;
 mod editor_message;
 mod editor_report;
@@ -26,33 +37,432 @@ mod verify_email;
 pub(crate) enum TemplateError {
     #[error("Failed to parse parameters: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("Template can't be collected into a digest: {0}")]
+    NotDigestible(String),
+    #[error("Invalid params: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    InvalidParams(Vec<InvalidParam>),
 }
 
-type Template = fn(Value, Locale) -> Result<Mjml, TemplateError>;
+impl TemplateError {
+    /// Paths of the params which were missing, unknown or invalid
+    pub(crate) fn fields(&self) -> Vec<String> {
+        match self {
+            Self::InvalidParams(params) => params.iter().map(|p| p.path.clone()).collect(),
+            _ => Vec::new(),
+        }
+    }
 
-pub fn get(template_id: &str) -> Option<Template> {
+    /// Places the paths of invalid params under those of an enclosing template
+    pub(crate) fn within(self, parent: &str) -> Self {
+        match self {
+            Self::InvalidParams(params) => Self::InvalidParams(
+                params
+                    .into_iter()
+                    .map(|param| InvalidParam {
+                        path: join_path(parent, &param.path),
+                        ..param
+                    })
+                    .collect(),
+            ),
+            e => e,
+        }
+    }
+}
+
+/// A param which a template couldn't accept
+#[derive(Debug)]
+pub(crate) struct InvalidParam {
+    /// Where the param is, such as `edits.artist[0].entity_name`
+    pub path: String,
+    pub reason: String,
+}
+
+impl Display for InvalidParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// The data a template is rendered with
+///
+/// Fields a template requires have no `#[serde(default)]`. Params are
+/// strict unless the caller opts in to the lenient handling of old:
+/// missing fields are left empty and unknown ones are ignored.
+#[derive(Debug, Clone)]
+pub(crate) struct Params {
+    value: Value,
+    lenient: bool,
+}
+
+impl Params {
+    pub(crate) fn new(value: Value, lenient: bool) -> Self {
+        Self { value, lenient }
+    }
+
+    pub(crate) fn strict(value: Value) -> Self {
+        Self::new(value, false)
+    }
+
+    pub(crate) fn lenient(value: Value) -> Self {
+        Self::new(value, true)
+    }
+
+    pub(crate) fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub(crate) fn parse<T: DeserializeOwned + Serialize + Default>(
+        self,
+    ) -> Result<T, TemplateError> {
+        if self.lenient {
+            let value = match self.value {
+                Value::Null => serde_json::to_value(T::default())?,
+                Value::Object(given) => {
+                    let mut value = serde_json::to_value(T::default())?;
+                    if let Value::Object(defaults) = &mut value {
+                        defaults.extend(given);
+                    }
+                    value
+                }
+                given => given,
+            };
+            return Ok(serde_json::from_value(value)?);
+        }
+
+        let value = match self.value {
+            Value::Null => Value::Object(Map::new()),
+            value => value,
+        };
+        let mut invalid = Vec::new();
+        let unknown = |path: serde_ignored::Path| {
+            invalid.push(InvalidParam {
+                path: ignored_path(&path),
+                reason: "unknown field".to_owned(),
+            })
+        };
+        let parsed: Result<T, _> =
+            serde_path_to_error::deserialize(serde_ignored::Deserializer::new(value, unknown));
+        match parsed {
+            Ok(params) if invalid.is_empty() => return Ok(params),
+            Ok(_) => {}
+            Err(e) => {
+                let path = match e.path().to_string() {
+                    root if root == "." => String::new(),
+                    path => path,
+                };
+                let reason = e.into_inner().to_string();
+                // Missing fields are reported against the object they're missing from
+                let path = match reason
+                    .strip_prefix("missing field `")
+                    .and_then(|rest| rest.strip_suffix('`'))
+                {
+                    Some(field) => join_path(&path, field),
+                    None => path,
+                };
+                invalid.push(InvalidParam { path, reason });
+            }
+        }
+        Err(TemplateError::InvalidParams(invalid))
+    }
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    match (parent, key) {
+        ("", path) | (path, "") => path.to_owned(),
+        (parent, key) => format!("{parent}.{key}"),
+    }
+}
+
+/// Formats a path the same way as `serde_path_to_error`
+fn ignored_path(path: &serde_ignored::Path) -> String {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{index}]", ignored_path(parent)),
+        Path::Map { parent, key } => join_path(&ignored_path(parent), key),
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => ignored_path(parent),
+    }
+}
+
+/// The name and schema of a template's params
+fn schema_of<T: ToSchema>() -> (String, RefOr<Schema>) {
+    let mut schema = T::schema();
+    close(&mut schema);
+    (format!("{}Params", T::name()), schema)
+}
+
+/// Marks objects as taking no other fields, as strict params don't
+fn close(schema: &mut RefOr<Schema>) {
+    match schema {
+        RefOr::T(Schema::Object(object)) if !object.properties.is_empty() => {
+            object.additional_properties = Some(Box::new(AdditionalProperties::FreeForm(false)));
+            object.properties.values_mut().for_each(close);
+        }
+        RefOr::T(Schema::Array(array)) => {
+            if let ArrayItems::RefOrSchema(items) = &mut array.items {
+                close(items);
+            }
+        }
+        _ => {}
+    }
+}
+
+type Template = fn(Params, Locale) -> Result<Mjml, TemplateError>;
+
+/// Renders one event of a template as part of a digest
+type DigestEntry = fn(Params, Locale) -> Result<MjBodyChild, TemplateError>;
+
+/// A kind of optional mail which recipients can opt out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Category {
+    /// The site which owns the recipient's mail preferences
+    pub site: &'static str,
+    pub name: &'static str,
+}
+
+const fn category(site: &'static str, name: &'static str) -> Option<Category> {
+    Some(Category { site, name })
+}
+
+/// The site a template's mail is sent on behalf of
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Brand {
+    MusicBrainz,
+    ListenBrainz,
+    CritiqueBrainz,
+}
+
+impl Brand {
+    /// The name of the site, as used for sender defaults
+    pub const fn site(self) -> &'static str {
+        match self {
+            Self::MusicBrainz => "musicbrainz",
+            Self::ListenBrainz => "listenbrainz",
+            Self::CritiqueBrainz => "critiquebrainz",
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateCategory {
+    /// Mail the recipient needs, such as password resets and messages
+    Transactional,
+    /// Mail about activity the recipient follows, which is usually optional
+    Notification,
+}
+
+/// What a template declares about itself
+pub(crate) struct TemplateInfo {
+    pub id: &'static str,
+    /// The site the mail is sent on behalf of, unless that depends on the params
+    pub brand: Option<Brand>,
+    pub category: TemplateCategory,
+    pub description: &'static str,
+    /// What recipients opt out of to stop getting the mail, if it's optional
+    pub unsubscribe: Option<Category>,
+    pub render: Template,
+    /// How an event of the template is shown in a digest, if it can be collected into one
+    pub digest_entry: Option<DigestEntry>,
+    /// Params showing everything the template can hold
+    pub example: fn() -> Value,
+    /// The name and schema of the params
+    pub schema: fn() -> (String, RefOr<Schema>),
+}
+
+/// Every template, in the order they are listed
+static TEMPLATES: &[TemplateInfo] = &[
+    basic::TEMPLATE,
+    cb_review::TEMPLATE,
+    digest::TEMPLATE,
+    subscription::TEMPLATE,
+    edit_note::TEMPLATE,
+    follow::TEMPLATE,
+    editor_message::TEMPLATE,
+    verify_email::TEMPLATE,
+    email_in_use::TEMPLATE,
+    reset_password::TEMPLATE,
+    lost_username::TEMPLATE,
+    no_vote::TEMPLATE,
+    notification::TEMPLATE,
+    editor_report::TEMPLATE,
+    personal_recommendation::TEMPLATE,
+    playlist_notification::TEMPLATE,
+    recording_pin::TEMPLATE,
+    recording_recommendation::TEMPLATE,
+    thanks::TEMPLATE,
+];
+
+/// Every template, as listed by `GET /templates`
+pub(crate) fn all() -> &'static [TemplateInfo] {
+    TEMPLATES
+}
+
+/// What a template declares about itself, if it exists
+pub(crate) fn info(template_id: &str) -> Option<&'static TemplateInfo> {
+    TEMPLATES.iter().find(|template| template.id == template_id)
+}
+
+/// A template, as listed by `GET /templates`
+#[derive(Serialize, ToSchema, Debug)]
+pub struct TemplateMetadata {
+    /// The ID to send or render the template with
+    id: &'static str,
+    /// The site the mail is sent on behalf of, unless that depends on the params
+    brand: Option<Brand>,
+    category: TemplateCategory,
+    description: &'static str,
+    /// The unsubscribe category recipients can opt out of, if the mail is optional
+    unsubscribe_category: Option<&'static str>,
+    /// Whether events of the template can be collected into a digest
+    digestible: bool,
+    /// Params showing everything the template can hold, as the `GET` render routes use
+    example: Value,
+}
+
+impl From<&TemplateInfo> for TemplateMetadata {
+    fn from(template: &TemplateInfo) -> Self {
+        Self {
+            id: template.id,
+            brand: template.brand,
+            category: template.category,
+            description: template.description,
+            unsubscribe_category: template.unsubscribe.map(|category| category.name),
+            digestible: template.digest_entry.is_some(),
+            example: (template.example)(),
+        }
+    }
+}
+
+/// The params of every template, as its ID, the schema's name and the schema
+pub(crate) fn params_schemas() -> impl Iterator<Item = (&'static str, String, RefOr<Schema>)> {
+    TEMPLATES.iter().map(|template| {
+        let (name, schema) = (template.schema)();
+        (template.id, name, schema)
+    })
+}
+
+/// The name and schema of a template's params
+pub(crate) fn params_schema(template_id: &str) -> Option<(String, RefOr<Schema>)> {
+    info(template_id).map(|template| (template.schema)())
+}
+
+/// The example params of a template
+pub(crate) fn example(template_id: &str) -> Option<Value> {
+    info(template_id).map(|template| (template.example)())
+}
+
+/// The site a template's mail is sent on behalf of
+pub fn site(template_id: &str) -> Option<&'static str> {
+    info(template_id)?.brand.map(Brand::site)
+}
+
+/// The unsubscribe category of a template, if its mail is optional
+pub fn unsubscribe_category(template_id: &str) -> Option<Category> {
+    info(template_id)?.unsubscribe
+}
+
+/// The unsubscribe category of mail sent with a template and params, if it's optional
+///
+/// Digests take theirs from their events.
+pub fn mail_unsubscribe_category(template_id: &str, params: &Value) -> Option<Category> {
     match template_id {
-        "basic" => Some(basic::basic),
-        "cb-review" => Some(cb_review::cb_review),
-        "subscription" => Some(subscription::subscription),
-        "edit-note" => Some(edit_note::edit_note),
-        "follow" => Some(follow::follow),
-        "editor-message" => Some(editor_message::editor_message),
-        "verify-email" => Some(verify_email::verify_email),
-        "email-in-use" => Some(email_in_use::email_in_use),
-        "reset-password" => Some(reset_password::reset_password),
-        "lost-username" => Some(lost_username::lost_username),
-        "no-vote" => Some(no_vote::no_vote),
-        "notification" => Some(notification::notification),
-        "editor-report" => Some(editor_report::editor_report),
-        "personal-recommendation" => Some(personal_recommendation::personal_recommendation),
-        "playlist-notification" => Some(playlist_notification::playlist_notification),
-        "recording-pin" => Some(recording_pin::recording_pin),
-        "recording-recommendation" => Some(recording_recommendation::recording_recommendation),
-        "thanks" => Some(thanks::thanks),
-        _ => {
+        "digest" => digest::unsubscribe(params),
+        _ => unsubscribe_category(template_id),
+    }
+}
+
+/// How an event of a template is shown in a digest, if it can be collected into one
+pub fn digest_entry(template_id: &str) -> Option<DigestEntry> {
+    info(template_id)?.digest_entry
+}
+
+pub fn get(template_id: &str) -> Option<Template> {
+    match info(template_id) {
+        Some(template) => Some(template.render),
+        None => {
             tracing::warn!("Unknown email template requested: {}", template_id);
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize, Serialize, Debug, Default)]
+    struct Outer {
+        name: String,
+        #[serde(default)]
+        items: Vec<Inner>,
+    }
+
+    #[derive(Deserialize, Serialize, Debug, Default)]
+    struct Inner {
+        id: u32,
+    }
+
+    #[test]
+    fn invalid_params_are_located() {
+        let params = json!({ "name": "Jade", "items": [{ "id": 1 }, { "idd": 2 }] });
+        let error = Params::strict(params).parse::<Outer>().unwrap_err();
+        assert_eq!(error.fields(), ["items[1].idd", "items[1].id"]);
+
+        let error = Params::strict(Value::Null).parse::<Outer>().unwrap_err();
+        assert_eq!(
+            error.within("events[0].params").fields(),
+            ["events[0].params.name"]
+        );
+
+        let outer: Outer = Params::lenient(json!({ "nmae": "Jade" })).parse().unwrap();
+        assert_eq!(outer.name, "");
+    }
+
+    #[test]
+    fn examples_are_valid_params() {
+        for (i, template) in all().iter().enumerate() {
+            assert!(
+                all()[..i].iter().all(|other| other.id != template.id),
+                "{} is registered twice",
+                template.id
+            );
+            let example = (template.example)();
+            if let Err(e) = (template.render)(Params::strict(example.clone()), Locale::default()) {
+                panic!("The example of {} is not valid: {e}", template.id);
+            }
+            // Events take part of the template's params, so the rest is ignored
+            if let Some(entry) = template.digest_entry {
+                assert!(entry(Params::lenient(example), Locale::default()).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn digests_share_the_category_of_their_events() {
+        let digest = |template_ids: &[&str]| {
+            let events: Vec<Value> = template_ids
+                .iter()
+                .map(|id| json!({ "template_id": id }))
+                .collect();
+            mail_unsubscribe_category("digest", &json!({ "events": events }))
+        };
+        assert_eq!(
+            digest(&["edit-note", "edit-note"]),
+            unsubscribe_category("edit-note")
+        );
+        assert!(digest(&["edit-note"]).is_some());
+        assert_eq!(digest(&["edit-note", "notification"]), None);
+        assert_eq!(digest(&[]), None);
+        assert_eq!(
+            mail_unsubscribe_category("edit-note", &Value::Null),
+            unsubscribe_category("edit-note")
+        );
+    }
+}
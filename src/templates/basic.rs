@@ -1,12 +1,35 @@
+use crate::Locale;
 use mrml::mjml;
 use mrmx_macros::view;
-use serde_json::Value;
+use serde_json::{json, Value};
+use utoipa::openapi::{schema::ObjectBuilder, RefOr, Schema};
 
-use crate::Locale;
+use super::{Params, TemplateCategory, TemplateError, TemplateInfo};
+
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "basic",
+    brand: None,
+    category: TemplateCategory::Transactional,
+    description: "A fixed test message",
+    unsubscribe: None,
+    render: basic,
+    digest_entry: None,
+    example,
+    schema,
+};
 
-use super::TemplateError;
+fn example() -> Value {
+    json!({})
+}
+
+fn schema() -> (String, RefOr<Schema>) {
+    let schema = ObjectBuilder::new()
+        .description(Some("The basic template takes no params"))
+        .into();
+    ("BasicParams".to_owned(), schema)
+}
 
-pub(crate) fn basic(_: Value, _l: Locale) -> Result<mjml::Mjml, TemplateError> {
+pub(crate) fn basic(_: Params, _l: Locale) -> Result<mjml::Mjml, TemplateError> {
     Ok(view! {
         <mjml>
           <mj-body>
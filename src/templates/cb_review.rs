@@ -5,16 +5,16 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct CbReview {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct CbReview {
     to_name: String,
     from_name: String,
     entity_name: String,
@@ -22,15 +22,36 @@ struct CbReview {
     notification_settings_url: String,
 }
 
-pub(crate) fn cb_review(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<CbReview> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "cb-review",
+    brand: Some(Brand::CritiqueBrainz),
+    category: TemplateCategory::Notification,
+    description: "A review written by a user the recipient follows",
+    unsubscribe: None,
+    render: cb_review,
+    digest_entry: None,
+    example,
+    schema: schema_of::<CbReview>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "entity_name": "Random Access Memories",
+        "entity_url": "https://critiquebrainz.org/review/0d1b4d4a-8f0e-4bd3-9c8a-7d0d0e4c3f4b",
+        "notification_settings_url": "https://critiquebrainz.org/profile/edit"
+    })
+}
+
+pub(crate) fn cb_review(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let CbReview {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
         entity_name: ref entity_name_raw,
         entity_url,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
     let from_name = &encode_text(from_name_raw);
@@ -0,0 +1,148 @@
+use std::borrow::Borrow;
+
+use html_escape::encode_text;
+use mf1::t_l_string as tl;
+use mrml::{mjml::Mjml, text::Text};
+use mrmx::WithAttribute;
+use mrmx_macros::view;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
+
+use crate::{components::*, Locale};
+
+use super::{
+    digest_entry, schema_of, unsubscribe_category, Category, Params, TemplateCategory,
+    TemplateError, TemplateInfo,
+};
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct Digest {
+    to_name: String,
+    settings_url: String,
+    #[schema(inline)]
+    events: Vec<DigestEvent>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+struct DigestEvent {
+    template_id: String,
+    #[serde(default)]
+    params: Value,
+}
+
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "digest",
+    brand: None,
+    category: TemplateCategory::Notification,
+    description: "Notifications collected over a period and sent as one mail",
+    // Depends on the events, see `unsubscribe`
+    unsubscribe: None,
+    render: digest,
+    digest_entry: None,
+    example,
+    schema: schema_of::<Digest>,
+};
+
+/// The unsubscribe category of a digest, if every event shares one
+pub(super) fn unsubscribe(params: &Value) -> Option<Category> {
+    let mut categories = params.get("events")?.as_array()?.iter().map(|event| {
+        event
+            .get("template_id")
+            .and_then(Value::as_str)
+            .and_then(unsubscribe_category)
+    });
+    let first = categories.next()??;
+    categories
+        .all(|category| category == Some(first))
+        .then_some(first)
+}
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "settings_url": "https://musicbrainz.org/account/preferences",
+        "events": [
+            {
+                "template_id": "edit-note",
+                "params": {
+                    "edit_id": 123456,
+                    "from_name": "rob",
+                    "message": "Is there a source for the release date?"
+                }
+            },
+            {
+                "template_id": "edit-note",
+                "params": {
+                    "edit_id": 123457,
+                    "from_name": "kim",
+                    "message": "Thanks, that looks right to me."
+                }
+            }
+        ]
+    })
+}
+
+pub(crate) fn digest(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
+    let lenient = params.is_lenient();
+    let Digest {
+        ref to_name,
+        settings_url,
+        events,
+    } = params.parse()?;
+
+    let to_name = &encode_text(to_name);
+    // Digests are collected per site, so the first event tells which one this is
+    let listenbrainz = events
+        .first()
+        .and_then(|event| unsubscribe_category(&event.template_id))
+        .is_some_and(|category| category.site == "listenbrainz");
+
+    let mut entries = view! {<></>};
+    for (
+        i,
+        DigestEvent {
+            template_id,
+            params,
+        },
+    ) in events.into_iter().enumerate()
+    {
+        let entry = digest_entry(&template_id).ok_or(TemplateError::NotDigestible(template_id))?;
+        let entry = entry(Params::new(params, lenient), l)
+            .map_err(|e| e.within(&format!("events[{i}].params")))?;
+        entries.children.push(entry);
+    }
+
+    Ok(view! {
+        <mjml>
+        <mj-head>
+            { head().into() }
+            <mj-title>{ tl!(l, digest.title).borrow() }</mj-title>
+        </mj-head>
+        <mj-body width="660px" padding="0">
+            <mj-section padding="20px 0">
+            <mj-column padding="0">
+                { if listenbrainz { lb_header().into() } else { header().into() } }
+
+                <mj-text font-size="14px">
+                    <p>{ Text::from(tl!(l, greeting_line, name = to_name)).into() }</p>
+                    <p>{ Text::from(tl!(l, digest.info)).into() }</p>
+                </mj-text>
+                { entries.into() }
+                <mj-text>
+                    <p><em>{ Text::from(tl!(l, metabrainz_signoff)).into() }</em></p>
+                </mj-text>
+                <mj-divider padding="10px 15px" border-color="#F5F5F5" border-width="3px" />
+                <mj-text font-size="12px" color="#8D8D8D">
+                    <p>{ Text::from(tl!(l, digest.about)).into() }</p>
+                    <p>
+                        <a href={settings_url}>{ Text::from(tl!(l, change_notification_settings)).into() }</a>
+                    </p>
+                    <p>{ Text::from(tl!(l, do_not_reply)).into() }</p>
+                </mj-text>
+            </mj-column>
+          </mj-section>
+        </mj-body>
+      </mjml>
+    })
+}
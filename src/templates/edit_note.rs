@@ -2,19 +2,19 @@ use std::borrow::Borrow;
 
 use html_escape::encode_text;
 use mf1::t_l_string as tl;
-use mrml::{mjml::Mjml, text::Text};
+use mrml::{fragment::Fragment, mj_body::MjBodyChild, mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct EditNote {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct EditNote {
     to_name: String,
     // to_id: String,
     subscriptions_url: String,
@@ -23,19 +23,51 @@ struct EditNote {
     message: String,
 }
 
-pub(crate) fn edit_note(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<EditNote> = serde_json::from_value(params)?;
+/// The params of a note collected into a digest
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct EditNoteEvent {
+    edit_id: u32,
+    from_name: String,
+    message: String,
+}
+
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "edit-note",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Notification,
+    description: "A note left on an edit the recipient made or voted on",
+    unsubscribe: category("musicbrainz", "edit-notes"),
+    render: edit_note,
+    digest_entry: Some(edit_note_entry),
+    example,
+    schema: schema_of::<EditNote>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "subscriptions_url": "https://musicbrainz.org/account/preferences",
+        "edit_id": 123456,
+        "from_name": "rob",
+        "message": "Is there a source for the release date?"
+    })
+}
+
+pub(crate) fn edit_note(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let EditNote {
         to_name,
         subscriptions_url,
         edit_id,
         from_name,
         message,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = encode_text(&to_name);
-    let from_name = encode_text(&from_name);
-    let message = encode_text(&message);
+    let event = EditNoteEvent {
+        edit_id,
+        from_name,
+        message,
+    };
 
     Ok(view! {
         <mjml>
@@ -66,19 +98,9 @@ pub(crate) fn edit_note(params: Value, l: Locale) -> Result<Mjml, TemplateError>
 
                 <mj-text>
                     <p>{ Text::from(tl!(l, greeting_line, name = to_name)).into() }</p>
-                    <p>{ Text::from(tl!(l, edit_note.top, edit_id = edit_id.to_string() )).into() }</p>
                 </mj-text>
-
-                <mj-wrapper mj-class="wrapper" css-class="speech" >
-                    <mj-text>
-                        <strong >{ Text::from(from_name + ": ").into()}</strong>
-                        <p class="text-no-wrap" style="white-space: pre-wrap;">
-                            { Text::from(message).into()}
-                        </p>
-                    </mj-text>
-                </mj-wrapper>
+                { note(event, l).into() }
                 <mj-text>
-                    <p><a href={"https://musicbrainz.org/edit/".to_owned() + &edit_id.to_string()}>{ Text::from(tl!(l, edit_note.reply )).into() }</a></p>
                     <p><em>{ Text::from(tl!(l, metabrainz_signoff)).into() }</em></p>
                 </mj-text>
                 <mj-divider padding="10px 15px" border-color="#F5F5F5" border-width="3px" />
@@ -98,3 +120,40 @@ pub(crate) fn edit_note(params: Value, l: Locale) -> Result<Mjml, TemplateError>
 
     })
 }
+
+/// The note, as both its own mail and a digest show it
+fn note(
+    EditNoteEvent {
+        edit_id,
+        from_name,
+        message,
+    }: EditNoteEvent,
+    l: Locale,
+) -> Fragment<MjBodyChild> {
+    let from_name = encode_text(&from_name);
+    let message = encode_text(&message);
+
+    view! {
+        <>
+            <mj-text>
+                <p>{ Text::from(tl!(l, edit_note.top, edit_id = edit_id.to_string() )).into() }</p>
+            </mj-text>
+            <mj-wrapper mj-class="wrapper" css-class="speech" >
+                <mj-text>
+                    <strong >{ Text::from(from_name + ": ").into()}</strong>
+                    <p class="text-no-wrap" style="white-space: pre-wrap;">
+                        { Text::from(message).into()}
+                    </p>
+                </mj-text>
+            </mj-wrapper>
+            <mj-text>
+                <p><a href={"https://musicbrainz.org/edit/".to_owned() + &edit_id.to_string()}>{ Text::from(tl!(l, edit_note.reply )).into() }</a></p>
+            </mj-text>
+        </>
+    }
+}
+
+/// The note as an entry of a digest
+pub(super) fn edit_note_entry(params: Params, l: Locale) -> Result<MjBodyChild, TemplateError> {
+    Ok(note(params.parse()?, l).into())
+}
@@ -5,16 +5,16 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct EditorMessage {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct EditorMessage {
     to_name: String,
     from_name: String,
     subject: String,
@@ -24,6 +24,7 @@ struct EditorMessage {
     ///
     /// If this is true, the user should be able to
     /// reply to the email to reply to the message.
+    #[serde(default)]
     revealed_address: bool,
     /// If this is a copy for the message sender.
     ///
@@ -33,8 +34,31 @@ struct EditorMessage {
     is_self_copy: bool,
 }
 
-pub(crate) fn editor_message(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<EditorMessage> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "editor-message",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "A message sent to the recipient by another editor",
+    unsubscribe: None,
+    render: editor_message,
+    digest_entry: None,
+    example,
+    schema: schema_of::<EditorMessage>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "subject": "Your recent edits",
+        "message": "Hi Jade,\n\nThanks for adding the tracklist!",
+        "contact_url": "https://musicbrainz.org/user/rob/contact",
+        "revealed_address": true,
+        "is_self_copy": false
+    })
+}
+
+pub(crate) fn editor_message(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let EditorMessage {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
@@ -43,7 +67,7 @@ pub(crate) fn editor_message(params: Value, l: Locale) -> Result<Mjml, TemplateE
         contact_url,
         revealed_address,
         is_self_copy,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
     let from_name = &encode_text(from_name_raw);
@@ -5,16 +5,16 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct EditorReport {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct EditorReport {
     reported_name: String,
     from_name: String,
     reported_url: String,
@@ -24,6 +24,7 @@ struct EditorReport {
     ///
     /// If this is true, the user should be able to
     /// reply to the email to reply to the message.
+    #[serde(default)]
     revealed_address: bool,
     /// If this is a copy for the message sender.
     ///
@@ -33,8 +34,31 @@ struct EditorReport {
     is_self_copy: bool,
 }
 
-pub(crate) fn editor_report(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<EditorReport> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "editor-report",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "A report about an editor, for the account admins",
+    unsubscribe: None,
+    render: editor_report,
+    digest_entry: None,
+    example,
+    schema: schema_of::<EditorReport>,
+};
+
+fn example() -> Value {
+    json!({
+        "reported_name": "spammer",
+        "from_name": "rob",
+        "reported_url": "https://musicbrainz.org/user/spammer",
+        "from_url": "https://musicbrainz.org/user/rob",
+        "message": "This account only adds links to a shop.",
+        "revealed_address": true,
+        "is_self_copy": false
+    })
+}
+
+pub(crate) fn editor_report(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let EditorReport {
         reported_name: ref reported_name_raw,
         from_name: ref from_name_raw,
@@ -43,7 +67,7 @@ pub(crate) fn editor_report(params: Value, l: Locale) -> Result<Mjml, TemplateEr
         message,
         revealed_address,
         is_self_copy,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let reported_name = &encode_text(reported_name_raw);
     let from_name = &encode_text(from_name_raw);
@@ -5,29 +5,48 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct EmailInUse {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct EmailInUse {
     to_name: String,
     lost_username_url: String,
     lost_password_url: String,
     // ip?
 }
 
-pub(crate) fn email_in_use(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<EmailInUse> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "email-in-use",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "A warning that an address being verified already belongs to an account",
+    unsubscribe: None,
+    render: email_in_use,
+    digest_entry: None,
+    example,
+    schema: schema_of::<EmailInUse>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "lost_username_url": "https://musicbrainz.org/lost-username",
+        "lost_password_url": "https://musicbrainz.org/lost-password"
+    })
+}
+
+pub(crate) fn email_in_use(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let EmailInUse {
         ref to_name,
         ref lost_username_url,
         ref lost_password_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
 
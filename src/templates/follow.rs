@@ -2,36 +2,66 @@ use std::borrow::Borrow;
 
 use html_escape::encode_text;
 use mf1::t_l_string as tl;
-use mrml::{mjml::Mjml, text::Text};
+use mrml::{fragment::Fragment, mj_body::MjBodyChild, mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct Follow {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct Follow {
     to_name: String,
     from_name: String,
     from_url: String,
     notification_settings_url: String,
 }
 
-pub(crate) fn follow(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<Follow> = serde_json::from_value(params)?;
+/// The params of a follow collected into a digest
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct FollowEvent {
+    from_name: String,
+    from_url: String,
+}
+
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "follow",
+    brand: Some(Brand::ListenBrainz),
+    category: TemplateCategory::Notification,
+    description: "Another user started following the recipient",
+    unsubscribe: category("listenbrainz", "follows"),
+    render: follow,
+    digest_entry: Some(follow_entry),
+    example,
+    schema: schema_of::<Follow>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "from_url": "https://listenbrainz.org/user/rob/",
+        "notification_settings_url": "https://listenbrainz.org/settings/"
+    })
+}
+
+pub(crate) fn follow(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let Follow {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
         from_url,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
-    let from_name = &encode_text(from_name_raw);
+    let event = FollowEvent {
+        from_name: from_name_raw.clone(),
+        from_url,
+    };
 
     Ok(view! {
         <mjml>
@@ -46,17 +76,8 @@ pub(crate) fn follow(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
 
                 <mj-text font-size="14px">
                     <p>{ Text::from(tl!(l, greeting_line, name = to_name)).into() }</p>
-                    <p>{ Text::from(tl!(l, follow.info, from_name = from_name)).into() }</p>
                 </mj-text>
-
-                <mj-button
-                    href={from_url}
-                    background-color="#353070"
-                    border-radius="8px"
-                    font-size="14px"
-                >
-                    { Text::from(tl!(l, follow.button_text)).into() }
-                </mj-button>
+                { follow_body(event, l).into() }
 
                 <mj-text>
                     <p><em>{ Text::from(tl!(l, metabrainz_signoff)).into() }</em></p>
@@ -75,3 +96,35 @@ pub(crate) fn follow(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
       </mjml>
     })
 }
+
+/// The follow, as both its own mail and a digest show it
+fn follow_body(
+    FollowEvent {
+        from_name,
+        from_url,
+    }: FollowEvent,
+    l: Locale,
+) -> Fragment<MjBodyChild> {
+    let from_name = &encode_text(&from_name);
+
+    view! {
+        <>
+            <mj-text font-size="14px">
+                <p>{ Text::from(tl!(l, follow.info, from_name = from_name)).into() }</p>
+            </mj-text>
+            <mj-button
+                href={from_url}
+                background-color="#353070"
+                border-radius="8px"
+                font-size="14px"
+            >
+                { Text::from(tl!(l, follow.button_text)).into() }
+            </mj-button>
+        </>
+    }
+}
+
+/// The follow as an entry of a digest
+pub(super) fn follow_entry(params: Params, l: Locale) -> Result<MjBodyChild, TemplateError> {
+    Ok(follow_body(params.parse()?, l).into())
+}
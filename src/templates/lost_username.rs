@@ -5,27 +5,45 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct EmailInUse {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct LostUsername {
     to_name: String,
     lost_password_url: String,
     // ip?
 }
 
-pub(crate) fn lost_username(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<EmailInUse> = serde_json::from_value(params)?;
-    let EmailInUse {
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "lost-username",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "The username of the account an address belongs to, for someone who forgot it",
+    unsubscribe: None,
+    render: lost_username,
+    digest_entry: None,
+    example,
+    schema: schema_of::<LostUsername>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "lost_password_url": "https://musicbrainz.org/lost-password"
+    })
+}
+
+pub(crate) fn lost_username(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
+    let LostUsername {
         ref to_name,
         ref lost_password_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
 
@@ -5,16 +5,16 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct NoVote {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct NoVote {
     to_name: String,
     response_url: String,
     subscription_settings_url: String,
@@ -23,8 +23,30 @@ struct NoVote {
     close_time: String,
 }
 
-pub(crate) fn no_vote(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<NoVote> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "no-vote",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Notification,
+    description: "Another editor voted against an edit the recipient made",
+    unsubscribe: category("musicbrainz", "no-votes"),
+    render: no_vote,
+    digest_entry: None,
+    example,
+    schema: schema_of::<NoVote>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "response_url": "https://musicbrainz.org/edit/123456",
+        "subscription_settings_url": "https://musicbrainz.org/account/preferences",
+        "edit_id": 123456,
+        "voter_name": "rob",
+        "close_time": "2025-01-31 12:00 UTC"
+    })
+}
+
+pub(crate) fn no_vote(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let NoVote {
         ref to_name,
         ref response_url,
@@ -32,7 +54,7 @@ pub(crate) fn no_vote(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
         edit_id,
         ref voter_name,
         ref close_time,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
     let voter_name = &encode_text(voter_name);
@@ -2,37 +2,66 @@ use std::borrow::Borrow;
 
 use html_escape::encode_text;
 use mf1::t_l_string as tl;
-use mrml::{mjml::Mjml, text::Text};
+use mrml::{fragment::Fragment, mj_body::MjBodyChild, mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct Notification {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct Notification {
     to_name: String,
     from_name: String,
     message: String,
     notification_settings_url: String,
 }
 
-pub(crate) fn notification(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<Notification> = serde_json::from_value(params)?;
+/// The params of a message collected into a digest
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct NotificationEvent {
+    from_name: String,
+    message: String,
+}
+
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "notification",
+    brand: Some(Brand::ListenBrainz),
+    category: TemplateCategory::Notification,
+    description: "A message for the recipient from another user or the site",
+    unsubscribe: category("listenbrainz", "notifications"),
+    render: notification,
+    digest_entry: Some(notification_entry),
+    example,
+    schema: schema_of::<Notification>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "message": "Your year in music is ready!",
+        "notification_settings_url": "https://listenbrainz.org/settings/"
+    })
+}
+
+pub(crate) fn notification(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let Notification {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
         message,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
-    let from_name = &encode_text(from_name_raw);
-    let message = encode_text(&message);
+    let event = NotificationEvent {
+        from_name: from_name_raw.clone(),
+        message,
+    };
 
     Ok(view! {
         <mjml>
@@ -47,16 +76,8 @@ pub(crate) fn notification(params: Value, l: Locale) -> Result<Mjml, TemplateErr
 
                 <mj-text font-size="14px">
                     <p>{ Text::from(tl!(l, greeting_line, name = to_name)).into() }</p>
-                    <p>{ Text::from(tl!(l, notification.info, from_name = from_name)).into() }</p>
                 </mj-text>
-
-                <mj-wrapper mj-class="wrapper">
-                    <mj-text>
-                        <p style="white-space: pre-wrap;">
-                            { Text::from(message).into() }
-                        </p>
-                    </mj-text>
-                </mj-wrapper>
+                { message_body(event, l).into() }
 
                 <mj-text>
                     <p><em>{ Text::from(tl!(l, metabrainz_signoff)).into() }</em></p>
@@ -75,3 +96,32 @@ pub(crate) fn notification(params: Value, l: Locale) -> Result<Mjml, TemplateErr
       </mjml>
     })
 }
+
+/// The message, as both its own mail and a digest show it
+fn message_body(
+    NotificationEvent { from_name, message }: NotificationEvent,
+    l: Locale,
+) -> Fragment<MjBodyChild> {
+    let from_name = &encode_text(&from_name);
+    let message = encode_text(&message);
+
+    view! {
+        <>
+            <mj-text font-size="14px">
+                <p>{ Text::from(tl!(l, notification.info, from_name = from_name)).into() }</p>
+            </mj-text>
+            <mj-wrapper mj-class="wrapper">
+                <mj-text>
+                    <p style="white-space: pre-wrap;">
+                        { Text::from(message).into() }
+                    </p>
+                </mj-text>
+            </mj-wrapper>
+        </>
+    }
+}
+
+/// The message as an entry of a digest
+pub(super) fn notification_entry(params: Params, l: Locale) -> Result<MjBodyChild, TemplateError> {
+    Ok(message_body(params.parse()?, l).into())
+}
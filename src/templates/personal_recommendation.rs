@@ -5,28 +5,55 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct PersonalRecommendation {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct PersonalRecommendation {
     to_name: String,
     from_name: String,
+    #[serde(default)]
     message: String,
     track_name: String,
     track_artist: String,
+    #[serde(default)]
     track_url: String,
+    #[serde(default)]
     album_art_url: String,
     notification_settings_url: String,
 }
 
-pub(crate) fn personal_recommendation(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<PersonalRecommendation> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "personal-recommendation",
+    brand: Some(Brand::ListenBrainz),
+    category: TemplateCategory::Notification,
+    description: "A track another user recommended to the recipient",
+    unsubscribe: category("listenbrainz", "recommendations"),
+    render: personal_recommendation,
+    digest_entry: None,
+    example,
+    schema: schema_of::<PersonalRecommendation>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "message": "You have to hear this one!",
+        "track_name": "Windowlicker",
+        "track_artist": "Aphex Twin",
+        "track_url": "https://listenbrainz.org/player?recording_mbids=3ee8f5d3-2d9d-4b6c-9a8c-2e5a7d6f1e4b",
+        "album_art_url": "https://coverartarchive.org/release/1d6a7a58-ab48-4d6b-85a3-5b7f8e5f8a3c/front-250",
+        "notification_settings_url": "https://listenbrainz.org/settings/"
+    })
+}
+
+pub(crate) fn personal_recommendation(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let PersonalRecommendation {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
@@ -36,7 +63,7 @@ pub(crate) fn personal_recommendation(params: Value, l: Locale) -> Result<Mjml,
         track_url,
         album_art_url,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
     let from_name = &encode_text(from_name_raw);
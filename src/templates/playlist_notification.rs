@@ -5,29 +5,49 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct PlaylistNotification {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct PlaylistNotification {
     to_name: String,
     playlist_name: String,
     playlist_url: String,
     notification_settings_url: String,
 }
-pub(crate) fn playlist_notification(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<PlaylistNotification> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "playlist-notification",
+    brand: Some(Brand::ListenBrainz),
+    category: TemplateCategory::Notification,
+    description: "A playlist made for the recipient has been updated",
+    unsubscribe: category("listenbrainz", "playlists"),
+    render: playlist_notification,
+    digest_entry: None,
+    example,
+    schema: schema_of::<PlaylistNotification>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "playlist_name": "Weekly Jams",
+        "playlist_url": "https://listenbrainz.org/playlist/4b3f4a4e-7e8b-4d0b-9a86-0c4b0c3e9e3c/",
+        "notification_settings_url": "https://listenbrainz.org/settings/"
+    })
+}
+
+pub(crate) fn playlist_notification(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let PlaylistNotification {
         to_name: ref to_name_raw,
         playlist_name: ref playlist_name_raw,
         playlist_url,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
     let playlist_name = &encode_text(playlist_name_raw);
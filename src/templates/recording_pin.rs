@@ -5,28 +5,55 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct RecordingPin {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct RecordingPin {
     to_name: String,
     from_name: String,
     track_name: String,
     track_artist: String,
+    #[serde(default)]
     track_url: String,
+    #[serde(default)]
     album_art_url: String,
+    #[serde(default)]
     message: String,
     notification_settings_url: String,
 }
 
-pub(crate) fn recording_pin(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<RecordingPin> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "recording-pin",
+    brand: Some(Brand::ListenBrainz),
+    category: TemplateCategory::Notification,
+    description: "A track pinned by a user the recipient follows",
+    unsubscribe: category("listenbrainz", "pins"),
+    render: recording_pin,
+    digest_entry: None,
+    example,
+    schema: schema_of::<RecordingPin>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "from_name": "rob",
+        "message": "On repeat all week",
+        "track_name": "Windowlicker",
+        "track_artist": "Aphex Twin",
+        "track_url": "https://listenbrainz.org/player?recording_mbids=3ee8f5d3-2d9d-4b6c-9a8c-2e5a7d6f1e4b",
+        "album_art_url": "https://coverartarchive.org/release/1d6a7a58-ab48-4d6b-85a3-5b7f8e5f8a3c/front-250",
+        "notification_settings_url": "https://listenbrainz.org/settings/"
+    })
+}
+
+pub(crate) fn recording_pin(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let RecordingPin {
         to_name: ref to_name_raw,
         from_name: ref from_name_raw,
@@ -36,7 +63,7 @@ pub(crate) fn recording_pin(params: Value, l: Locale) -> Result<Mjml, TemplateEr
         track_url,
         album_art_url,
         notification_settings_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name_raw);
     let from_name = &encode_text(from_name_raw);
@@ -5,26 +5,44 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct ResetPassword {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct ResetPassword {
     to_name: String,
     reset_url: String,
 }
 
-pub(crate) fn reset_password(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<ResetPassword> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "reset-password",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "A link to reset the password of an account",
+    unsubscribe: None,
+    render: reset_password,
+    digest_entry: None,
+    example,
+    schema: schema_of::<ResetPassword>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "reset_url": "https://musicbrainz.org/reset-password?id=1&time=1700000000&key=0123456789abcdef"
+    })
+}
+
+pub(crate) fn reset_password(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let ResetPassword {
         ref to_name,
         ref reset_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
 
@@ -5,40 +5,47 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{category, schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct Subscription {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct Subscription {
     to_name: String,
     subscription_settings_url: String,
     edit_subscriptions_url: String,
     #[serde(default)]
+    #[schema(inline)]
     edits: EditTypes,
     #[serde(default)]
+    #[schema(inline)]
     deletes: Vec<DeleteItem>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
 struct EditTypes {
     #[serde(default)]
+    #[schema(inline)]
     artist: Vec<SubItem>,
     #[serde(default)]
+    #[schema(inline)]
     collection: Vec<SubItem>,
     #[serde(default)]
+    #[schema(inline)]
     label: Vec<SubItem>,
     #[serde(default)]
+    #[schema(inline)]
     series: Vec<SubItem>,
     #[serde(default)]
+    #[schema(inline)]
     editor: Vec<SubItem>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
 struct SubItem {
     entity_name: String,
     #[serde(default)]
@@ -51,7 +58,7 @@ struct SubItem {
     // all_url
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
 struct DeleteItem {
     item_type: String,
     entity_name: String,
@@ -65,7 +72,7 @@ struct DeleteItem {
     edit_url: Option<String>,
 }
 
-// #[derive(Deserialize, Debug, Default)]
+// #[derive(Deserialize, Serialize, Debug, Default)]
 // #[serde(rename_all = "lowercase")]
 // enum ItemType {
 //     Artist,
@@ -88,16 +95,62 @@ struct DeleteItem {
 //     }
 // }
 
-pub(crate) fn subscription(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<Subscription> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "subscription",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Notification,
+    description: "Edits to, and deletions of, entities the recipient subscribed to",
+    unsubscribe: category("musicbrainz", "subscriptions"),
+    render: subscription,
+    digest_entry: None,
+    example,
+    schema: schema_of::<Subscription>,
+};
 
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "subscription_settings_url": "https://musicbrainz.org/account/preferences",
+        "edit_subscriptions_url": "https://musicbrainz.org/user/Jade/subscriptions",
+        "edits": {
+            "artist": [
+                {
+                    "entity_name": "Nathan",
+                    "entity_comment": "English EDM artist",
+                    "entity_url": "https://musicbrainz.org/artist/8d8d8a80-f74f-4f21-a44c-518cd6944ed2/edits",
+                    "open_size": 2,
+                    "applied_size": 1
+                }
+            ],
+            "label": [
+                {
+                    "entity_name": "Warp",
+                    "entity_url": "https://musicbrainz.org/label/46f0f4cd-8aab-4b33-b698-f459faf64190/edits",
+                    "open_size": 0,
+                    "applied_size": 3
+                }
+            ]
+        },
+        "deletes": [
+            {
+                "item_type": "series",
+                "entity_name": "Example Series",
+                "reason": "Merged into another series",
+                "edit_id": 123456,
+                "edit_url": "https://musicbrainz.org/edit/123456"
+            }
+        ]
+    })
+}
+
+pub(crate) fn subscription(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let Subscription {
         ref to_name,
         subscription_settings_url,
         edit_subscriptions_url,
         edits,
         deletes,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
 
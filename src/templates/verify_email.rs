@@ -5,26 +5,44 @@ use mf1::t_l_string as tl;
 use mrml::{mjml::Mjml, text::Text};
 use mrmx::WithAttribute;
 use mrmx_macros::view;
-use serde::Deserialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utoipa::ToSchema;
 
 use crate::{components::*, Locale};
 
-use super::TemplateError;
+use super::{schema_of, Brand, Params, TemplateCategory, TemplateError, TemplateInfo};
 
-#[derive(Deserialize, Debug, Default)]
-#[serde(default)]
-struct VerifyEmail {
+#[derive(Deserialize, Serialize, ToSchema, Debug, Default)]
+pub(super) struct VerifyEmail {
     to_name: String,
     verification_url: String,
 }
 
-pub(crate) fn verify_email(params: Value, l: Locale) -> Result<Mjml, TemplateError> {
-    let ctx: Option<VerifyEmail> = serde_json::from_value(params)?;
+pub(super) const TEMPLATE: TemplateInfo = TemplateInfo {
+    id: "verify-email",
+    brand: Some(Brand::MusicBrainz),
+    category: TemplateCategory::Transactional,
+    description: "A link to verify a new or changed email address",
+    unsubscribe: None,
+    render: verify_email,
+    digest_entry: None,
+    example,
+    schema: schema_of::<VerifyEmail>,
+};
+
+fn example() -> Value {
+    json!({
+        "to_name": "Jade",
+        "verification_url": "https://musicbrainz.org/verify-email?userid=1&email=jade%40example.org&time=1700000000&chk=0123456789abcdef"
+    })
+}
+
+pub(crate) fn verify_email(params: Params, l: Locale) -> Result<Mjml, TemplateError> {
     let VerifyEmail {
         ref to_name,
         ref verification_url,
-    } = ctx.unwrap_or_default();
+    } = params.parse()?;
 
     let to_name = &encode_text(to_name);
 
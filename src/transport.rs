@@ -0,0 +1,225 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State, Json};
+use lettre::{
+    address::Envelope,
+    transport::smtp::response::{Category, Code, Detail, Response, Severity},
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use monostate::MustBe;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    queue::unique_id,
+    relays::{FailoverConfig, Relays},
+    serve::{AppState, SmtpMailerConfig},
+};
+
+pub type SmtpTransport = AsyncSmtpTransport<Tokio1Executor>;
+
+/// Where built mail should be delivered to
+///
+/// | Setting name | Value                                          | Default value    |
+/// | ------------ | ---------------------------------------------- | ---------------- |
+/// | mode         | `smtp` \| `file` \| `sendmail` \| `memory`     | `smtp`           |
+/// | dir          | `file`: directory to write `.eml` files into   | required         |
+/// | command      | `sendmail`: path to the sendmail binary        | `sendmail`       |
+///
+/// The `smtp` mode is configured through [`SmtpMailerConfig`], or a list
+/// of relays to fail over between.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TransportConfig {
+    File {
+        #[allow(dead_code)]
+        mode: MustBe!("file"),
+        dir: PathBuf,
+    },
+    Sendmail {
+        #[allow(dead_code)]
+        mode: MustBe!("sendmail"),
+        command: Option<PathBuf>,
+    },
+    Memory {
+        #[allow(dead_code)]
+        mode: MustBe!("memory"),
+    },
+    Smtp {
+        #[allow(dead_code)]
+        mode: Option<MustBe!("smtp")>,
+    },
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self::Smtp { mode: None }
+    }
+}
+
+/// A message kept by the `memory` transport
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct CapturedMail {
+    id: String,
+    /// Envelope sender
+    from: Option<String>,
+    /// Envelope recipients
+    to: Vec<String>,
+    /// The full RFC 5322 message
+    message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum TransportError {
+    #[error("{0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("{0}")]
+    File(#[from] lettre::transport::file::Error),
+    #[error("{0}")]
+    Sendmail(#[from] lettre::transport::sendmail::Error),
+}
+
+impl TransportError {
+    /// Whether retrying the same message can never succeed
+    pub(crate) fn is_permanent(&self) -> bool {
+        match self {
+            TransportError::Smtp(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
+
+    /// How the failure is reported to clients
+    ///
+    /// Relays which can't be reached, or which refuse mail for now, may accept it later.
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            TransportError::Smtp(e) if e.is_permanent() => ErrorCode::SmtpPermanent,
+            TransportError::Smtp(_) => ErrorCode::SmtpTransient,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The reply code, if the error came from an SMTP server
+    pub(crate) fn smtp_code(&self) -> Option<u16> {
+        match self {
+            TransportError::Smtp(e) => e.status().map(u16::from),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Transport {
+    Smtp(Arc<Relays>),
+    File(Arc<AsyncFileTransport<Tokio1Executor>>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    Memory(Arc<Mutex<Vec<CapturedMail>>>),
+}
+
+/// The response given by transports which don't speak SMTP
+fn accepted(message: String) -> Response {
+    Response::new(
+        Code::new(
+            Severity::PositiveCompletion,
+            Category::MailSystem,
+            Detail::Zero,
+        ),
+        vec![message],
+    )
+}
+
+impl Transport {
+    pub(crate) fn new(
+        config: TransportConfig,
+        relays: Vec<SmtpMailerConfig>,
+        failover: &FailoverConfig,
+    ) -> Self {
+        match config {
+            TransportConfig::Smtp { .. } => Self::Smtp(Arc::new(Relays::new(relays, failover))),
+            TransportConfig::File { dir, .. } => {
+                std::fs::create_dir_all(&dir).expect("failed to create mail directory");
+                Self::File(Arc::new(AsyncFileTransport::new(dir)))
+            }
+            TransportConfig::Sendmail {
+                command: Some(command),
+                ..
+            } => Self::Sendmail(AsyncSendmailTransport::new_with_command(command)),
+            TransportConfig::Sendmail { command: None, .. } => {
+                Self::Sendmail(AsyncSendmailTransport::new())
+            }
+            TransportConfig::Memory { .. } => Self::Memory(Default::default()),
+        }
+    }
+
+    pub(crate) async fn send_raw(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<Response, TransportError> {
+        Ok(match self {
+            Transport::Smtp(relays) => relays.send_raw(envelope, email).await?,
+            Transport::File(file) => {
+                let id = file.send_raw(envelope, email).await?;
+                accepted(format!("Written as {id}.eml"))
+            }
+            Transport::Sendmail(sendmail) => {
+                sendmail.send_raw(envelope, email).await?;
+                accepted("Handed to sendmail".to_owned())
+            }
+            Transport::Memory(store) => {
+                let id = unique_id();
+                store.lock().unwrap().push(CapturedMail {
+                    id: id.clone(),
+                    from: envelope.from().map(|a| a.to_string()),
+                    to: envelope.to().iter().map(|a| a.to_string()).collect(),
+                    message: String::from_utf8_lossy(email).into_owned(),
+                });
+                accepted(format!("Captured as {id}"))
+            }
+        })
+    }
+
+    pub(crate) async fn send(&self, email: Message) -> Result<Response, TransportError> {
+        self.send_raw(email.envelope(), &email.formatted()).await
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/captured_mail",
+    responses(
+        (status = 200, description = "All mail captured by the memory transport", body = [CapturedMail]),
+        (status = NOT_FOUND, description = "The memory transport is not in use")
+    )
+)]
+pub async fn captured_mail_route(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<CapturedMail>>, ApiError> {
+    match &state.mailer {
+        Transport::Memory(store) => Ok(Json(store.lock().unwrap().clone())),
+        _ => Err(ApiError::not_found("Mail is not being captured")),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/captured_mail",
+    responses(
+        (status = 200, description = "Captured mail was cleared", body = [CapturedMail]),
+        (status = NOT_FOUND, description = "The memory transport is not in use")
+    )
+)]
+pub async fn clear_captured_mail_route(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<CapturedMail>>, ApiError> {
+    match &state.mailer {
+        Transport::Memory(store) => Ok(Json(std::mem::take(&mut *store.lock().unwrap()))),
+        _ => Err(ApiError::not_found("Mail is not being captured")),
+    }
+}
@@ -0,0 +1,385 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use lettre::{
+    message::header::{HeaderName, HeaderValue},
+    Address,
+};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    queue::now,
+    serve::AppState,
+    serve::Secret,
+    templates::{self, Category},
+};
+
+/// One-click unsubscribe links
+///
+/// | Setting name | Value                                                    | Default value       |
+/// | ------------ | -------------------------------------------------------- | ------------------- |
+/// | secret       | key used to sign unsubscribe links                       | unset (off)         |
+/// | secret.file  | path to a file containing the key                        | unset (off)         |
+/// | url          | public base URL of this service                          | unset (off)         |
+/// | validity     | seconds an unsubscribe link stays valid                  | `7776000` (90 days) |
+/// | callbacks    | map of site name to the URL opt-outs are forwarded to    | empty               |
+/// | log          | file each opt-out is appended to, as a line of JSON      | unset               |
+///
+/// Links are only added to mail when both `secret` and `url` are set. Opt-outs
+/// must then be recorded somewhere: either `log` is set, or every site with
+/// optional mail has a callback.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct UnsubscribeConfig {
+    pub secret: Option<Secret>,
+    pub url: Option<String>,
+    pub validity: u64,
+    pub callbacks: HashMap<String, String>,
+    pub log: Option<PathBuf>,
+}
+
+impl Default for UnsubscribeConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            url: None,
+            validity: 90 * 24 * 60 * 60,
+            callbacks: HashMap::new(),
+            log: None,
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What an unsubscribe token grants
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct OptOut {
+    /// The address which is opting out
+    recipient: String,
+    site: String,
+    category: String,
+    /// Unix time after which the token is no longer accepted
+    expires: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum UnsubscribeError {
+    #[error("Unsubscribe links are not enabled")]
+    Disabled,
+    #[error("Invalid unsubscribe link")]
+    InvalidToken,
+    #[error("Unsubscribe link has expired")]
+    Expired,
+    #[error("Failed to record opt-out: {0}")]
+    Record(#[from] std::io::Error),
+    #[error("Failed to forward opt-out: {0}")]
+    Callback(#[from] reqwest::Error),
+    #[error("Opt-outs from {0} can't be recorded, as it has no callback and there is no log")]
+    Unrecorded(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum UnsubscribeSetupError {
+    #[error("Failed to read unsubscribe secret: {0}")]
+    Secret(#[from] std::io::Error),
+    #[error("Opt-outs from {0} would not be recorded. Set a log, or a callback for each site.")]
+    Unrecorded(String),
+}
+
+impl IntoResponse for UnsubscribeError {
+    fn into_response(self) -> axum::response::Response {
+        let code = match self {
+            Self::Disabled => ErrorCode::NotFound,
+            Self::InvalidToken => ErrorCode::BadRequest,
+            Self::Expired => ErrorCode::Gone,
+            Self::Record(_) | Self::Unrecorded(_) => ErrorCode::Internal,
+            Self::Callback(_) => ErrorCode::UpstreamFailed,
+        };
+        ApiError::from_error(code, &self).into_response()
+    }
+}
+
+/// Issues and redeems signed, expiring unsubscribe tokens
+#[derive(Default)]
+pub(crate) struct Unsubscribe {
+    key: Option<Vec<u8>>,
+    url: Option<String>,
+    validity: u64,
+    callbacks: HashMap<String, String>,
+    log: Option<PathBuf>,
+    client: reqwest::Client,
+}
+
+impl Unsubscribe {
+    pub(crate) fn new(config: UnsubscribeConfig) -> Result<Self, UnsubscribeSetupError> {
+        let unsubscribe = Self {
+            key: config
+                .secret
+                .map(|secret| secret.read().map(String::into_bytes))
+                .transpose()?,
+            url: config.url.map(|url| url.trim_end_matches('/').to_owned()),
+            validity: config.validity,
+            callbacks: config.callbacks,
+            log: config.log,
+            client: reqwest::Client::new(),
+        };
+        if unsubscribe.key.is_some() && unsubscribe.url.is_some() && unsubscribe.log.is_none() {
+            let mut unrecorded: Vec<&str> = templates::all()
+                .iter()
+                .filter_map(|template| template.unsubscribe)
+                .map(|category| category.site)
+                .filter(|site| !unsubscribe.callbacks.contains_key(*site))
+                .collect();
+            unrecorded.sort();
+            unrecorded.dedup();
+            if !unrecorded.is_empty() {
+                return Err(UnsubscribeSetupError::Unrecorded(unrecorded.join(", ")));
+            }
+        }
+        Ok(unsubscribe)
+    }
+
+    fn mac(&self) -> Option<HmacSha256> {
+        let key = self.key.as_ref()?;
+        Some(HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length"))
+    }
+
+    fn token(&self, opt_out: &OptOut) -> Option<String> {
+        let payload = serde_json::to_vec(opt_out).expect("opt-outs can be serialized");
+        let mut mac = self.mac()?;
+        mac.update(&payload);
+        let signature = mac.finalize().into_bytes();
+        Some(format!(
+            "{}.{}",
+            BASE64.encode(payload),
+            BASE64.encode(signature)
+        ))
+    }
+
+    fn verify(&self, token: &str) -> Result<OptOut, UnsubscribeError> {
+        let mut mac = self.mac().ok_or(UnsubscribeError::Disabled)?;
+        let (payload, signature) = token
+            .split_once('.')
+            .ok_or(UnsubscribeError::InvalidToken)?;
+        let payload = BASE64
+            .decode(payload)
+            .map_err(|_| UnsubscribeError::InvalidToken)?;
+        let signature = BASE64
+            .decode(signature)
+            .map_err(|_| UnsubscribeError::InvalidToken)?;
+        mac.update(&payload);
+        mac.verify_slice(&signature)
+            .map_err(|_| UnsubscribeError::InvalidToken)?;
+
+        let opt_out: OptOut =
+            serde_json::from_slice(&payload).map_err(|_| UnsubscribeError::InvalidToken)?;
+        if opt_out.expires < now() {
+            return Err(UnsubscribeError::Expired);
+        }
+        Ok(opt_out)
+    }
+
+    /// `List-Unsubscribe` headers for mail of a category, if links are enabled
+    ///
+    /// A link opts out the one recipient it was signed for, so mail with
+    /// several recipients gets no link rather than one which would opt out
+    /// whoever is listed first.
+    pub(crate) fn headers(
+        &self,
+        category: Option<Category>,
+        recipients: &[Address],
+    ) -> Vec<HeaderValue> {
+        let (Some(category), Some(url), [recipient]) = (category, &self.url, recipients) else {
+            return Vec::new();
+        };
+        let Some(token) = self.token(&OptOut {
+            recipient: recipient.to_string(),
+            site: category.site.to_owned(),
+            category: category.name.to_owned(),
+            expires: now() + self.validity,
+        }) else {
+            return Vec::new();
+        };
+        vec![
+            HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                format!("<{url}/unsubscribe/{token}>"),
+            ),
+            // RFC 8058 one-click unsubscribe
+            HeaderValue::new(
+                HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                "List-Unsubscribe=One-Click".to_owned(),
+            ),
+        ]
+    }
+
+    /// Records an opt-out and passes it on to the site which owns it
+    async fn redeem(&self, opt_out: &OptOut) -> Result<(), UnsubscribeError> {
+        let callback = self.callbacks.get(&opt_out.site);
+        if callback.is_none() && self.log.is_none() {
+            return Err(UnsubscribeError::Unrecorded(opt_out.site.clone()));
+        }
+        info!(
+            "{} unsubscribed from {}/{}",
+            opt_out.recipient, opt_out.site, opt_out.category
+        );
+        counter!("unsubscribes_total", "site" => opt_out.site.clone(), "category" => opt_out.category.clone())
+            .increment(1);
+
+        if let Some(log) = &self.log {
+            let mut line = serde_json::to_vec(opt_out).expect("opt-outs can be serialized");
+            line.push(b'\n');
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log)
+                .await?
+                .write_all(&line)
+                .await?;
+        }
+
+        match callback {
+            Some(callback) => {
+                self.client
+                    .post(callback)
+                    .json(opt_out)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            None => tracing::warn!("No unsubscribe callback for {}", opt_out.site),
+        }
+        Ok(())
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/unsubscribe/{token}",
+    responses(
+        (status = 200, description = "The recipient was unsubscribed"),
+        (status = BAD_REQUEST, description = "The link is invalid"),
+        (status = GONE, description = "The link has expired"),
+        (status = BAD_GATEWAY, description = "The site owning the recipient's preferences could not be reached")
+    ),
+    params(
+        ("token" = String, Path, description = "Token from the List-Unsubscribe header"),
+    )
+)]
+pub async fn unsubscribe_route(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<&'static str, UnsubscribeError> {
+    let opt_out = state.unsubscribe.verify(&token)?;
+    state.unsubscribe.redeem(&opt_out).await?;
+    Ok("Unsubscribed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsubscribe(validity: u64) -> Unsubscribe {
+        Unsubscribe::new(UnsubscribeConfig {
+            secret: Some(Secret::Inline("hunter2".to_owned())),
+            url: Some("https://mail.example.org/".to_owned()),
+            validity,
+            log: Some(std::env::temp_dir().join("mb-mail-unsubscribes.jsonl")),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn opt_out(expires: u64) -> OptOut {
+        OptOut {
+            recipient: "jade@example.org".to_owned(),
+            site: "musicbrainz".to_owned(),
+            category: "subscriptions".to_owned(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn token_round_trips() {
+        let unsubscribe = unsubscribe(60);
+        let expires = now() + 60;
+        let token = unsubscribe.token(&opt_out(expires)).unwrap();
+        assert_eq!(unsubscribe.verify(&token).unwrap(), opt_out(expires));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let unsubscribe = unsubscribe(60);
+        let token = unsubscribe.token(&opt_out(now() + 60)).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = serde_json::to_vec(&OptOut {
+            recipient: "rob@example.org".to_owned(),
+            ..opt_out(now() + 60)
+        })
+        .unwrap();
+        let forged = format!("{}.{signature}", BASE64.encode(forged));
+        assert!(matches!(
+            unsubscribe.verify(&forged),
+            Err(UnsubscribeError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let unsubscribe = unsubscribe(60);
+        let token = unsubscribe.token(&opt_out(now() - 1)).unwrap();
+        assert!(matches!(
+            unsubscribe.verify(&token),
+            Err(UnsubscribeError::Expired)
+        ));
+    }
+
+    #[test]
+    fn opt_outs_must_be_recorded() {
+        let config = || UnsubscribeConfig {
+            secret: Some(Secret::Inline("hunter2".to_owned())),
+            url: Some("https://mail.example.org/".to_owned()),
+            callbacks: HashMap::from([(
+                "musicbrainz".to_owned(),
+                "https://musicbrainz.org/unsubscribe".to_owned(),
+            )]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Unsubscribe::new(config()),
+            Err(UnsubscribeSetupError::Unrecorded(sites)) if sites == "listenbrainz"
+        ));
+
+        let mut config = config();
+        config.callbacks.insert(
+            "listenbrainz".to_owned(),
+            "https://listenbrainz.org/unsubscribe".to_owned(),
+        );
+        assert!(Unsubscribe::new(config).is_ok());
+        assert!(Unsubscribe::new(UnsubscribeConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn headers_are_only_added_for_categories() {
+        let unsubscribe = unsubscribe(60);
+        let category = crate::templates::unsubscribe_category("subscription");
+        let jade: Address = "jade@example.org".parse().unwrap();
+        let rob: Address = "rob@example.org".parse().unwrap();
+        let headers = unsubscribe.headers(category, std::slice::from_ref(&jade));
+        assert_eq!(headers.len(), 2);
+        assert!(unsubscribe.headers(None, &[jade.clone()]).is_empty());
+        // One link can't opt out each of several recipients
+        assert!(unsubscribe.headers(category, &[jade, rob]).is_empty());
+    }
+}